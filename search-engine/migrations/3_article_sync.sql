@@ -0,0 +1,11 @@
+-- The search index is synced incrementally from this table, ordered by `(updated_at, id)`, so every change to a row
+-- has to bump `updated_at`. The trigger from `1_setup.sql` takes care of that on update.
+alter table "article"
+    add column updated_at timestamptz not null default now();
+
+select trigger_updated_at('"article"');
+
+-- Articles are upserted into the index by `id`, so it has to identify a single row.
+create unique index article_id_key on "article" (id);
+
+create index article_updated_at_id_idx on "article" (updated_at, id);
@@ -21,16 +21,36 @@
 //! // the "emoji" is dropped because its not an alphanum
 //! assert!(stream.next().is_none());
 //! ```
+//!
+//! `AlphaOnlyFilter` is one preset of the configurable `CharacterClassFilter`:
+//! ```rust
+//! use search_engine::alpha_only_filter::{CharacterClassFilter, FilterMode};
+//! use tantivy::tokenizer::*;
+//! let mut tokenizer = TextAnalyzer::builder(SimpleTokenizer::default())
+//!   .filter(CharacterClassFilter::ALPHANUMERIC)
+//!   .build();
+//!
+//! let mut stream = tokenizer.token_stream("covid19 2023");
+//! assert_eq!(stream.next().unwrap().text, "covid19");
+//! assert_eq!(stream.next().unwrap().text, "2023");
+//!
+//! let mut tokenizer = TextAnalyzer::builder(RawTokenizer::default())
+//!   .filter(CharacterClassFilter {
+//!       mode: FilterMode::Strip,
+//!       ..CharacterClassFilter::ALPHA_ONLY
+//!   })
+//!   .build();
+//!
+//! let mut stream = tokenizer.token_stream("g7-2023");
+//! assert_eq!(stream.next().unwrap().text, "g");
+//! ```
 use tantivy::tokenizer::{Token, TokenFilter, TokenStream, Tokenizer};
 
-/// `TokenFilter` that removes all tokens that contain non
-/// ascii alphanumeric characters.
+/// `TokenFilter` that removes all tokens that contain characters outside of
+/// the Vietnamese alphabet, see `CharacterClassFilter::ALPHA_ONLY`.
 #[derive(Clone)]
 pub struct AlphaOnlyFilter;
 
-pub struct AlphaOnlyFilterStream<T> {
-    tail: T,
-}
 const SUPPORTED_CHARACTERS: [char; 93] = [
     'a', 'á', 'à', 'ả', 'ã', 'ạ', 'ă', 'ắ', 'ằ', 'ẳ', 'ẵ', 'ặ', 'â', 'ấ', 'ầ', 'ẩ', 'ẫ', 'ậ', 'b',
     'c', 'd', 'đ', 'e', 'é', 'è', 'ẻ', 'ẽ', 'ẹ', 'ê', 'ế', 'ề', 'ể', 'ễ', 'ệ', 'f', 'g', 'h', 'i',
@@ -39,42 +59,129 @@ const SUPPORTED_CHARACTERS: [char; 93] = [
     'ụ', 'ư', 'ứ', 'ừ', 'ử', 'ữ', 'ự', 'v', 'w', 'x', 'y', 'ý', 'ỳ', 'ỷ', 'ỹ', 'ỵ', 'z',
 ];
 
-impl<T> AlphaOnlyFilterStream<T> {
-    fn predicate(&self, token: &Token) -> bool {
-        token
-            .text
-            .chars()
-            .all(|c| SUPPORTED_CHARACTERS.contains(&c))
+/// Every lowercase Vietnamese letter lies below U+2000, one bit per code point.
+const TABLE_SIZE: usize = 0x2000 / 64;
+
+/// Bitmap of the non ascii letters of `SUPPORTED_CHARACTERS`.
+const VIETNAMESE_TABLE: [u64; TABLE_SIZE] = {
+    let mut table = [0u64; TABLE_SIZE];
+    let mut i = 0;
+    while i < SUPPORTED_CHARACTERS.len() {
+        let c = SUPPORTED_CHARACTERS[i];
+        if !c.is_ascii() {
+            table[c as usize / 64] |= 1 << (c as usize % 64);
+        }
+        i += 1;
+    }
+    table
+};
+
+fn is_vietnamese_letter(c: char) -> bool {
+    let c = c as usize;
+    c / 64 < TABLE_SIZE && VIETNAMESE_TABLE[c / 64] & (1 << (c % 64)) != 0
+}
+
+/// What happens to a token holding characters that are not allowed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FilterMode {
+    /// The whole token is removed.
+    Reject,
+    /// The characters are removed from the token, which is only removed when nothing is left.
+    Strip,
+}
+
+/// `TokenFilter` only keeping the characters of the enabled classes, in either case.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CharacterClassFilter {
+    /// Ascii letters.
+    pub latin: bool,
+    /// Letters with Vietnamese diacritics and `đ`.
+    pub vietnamese: bool,
+    /// Ascii digits.
+    pub digits: bool,
+    pub mode: FilterMode,
+}
+
+impl CharacterClassFilter {
+    /// Vietnamese words only, tokens holding digits or symbols are rejected.
+    pub const ALPHA_ONLY: CharacterClassFilter = CharacterClassFilter {
+        latin: true,
+        vietnamese: true,
+        digits: false,
+        mode: FilterMode::Reject,
+    };
+
+    /// Vietnamese words, numbers and mixed tokens such as "covid19" or "5g".
+    pub const ALPHANUMERIC: CharacterClassFilter = CharacterClassFilter {
+        digits: true,
+        ..CharacterClassFilter::ALPHA_ONLY
+    };
+
+    pub fn allows(&self, c: char) -> bool {
+        if c.is_ascii() {
+            return (self.latin && c.is_ascii_alphabetic()) || (self.digits && c.is_ascii_digit());
+        }
+        self.vietnamese
+            && (is_vietnamese_letter(c)
+                || c.is_uppercase() && c.to_lowercase().all(is_vietnamese_letter))
     }
 }
 
 impl TokenFilter for AlphaOnlyFilter {
-    type Tokenizer<T: Tokenizer> = AlphaOnlyFilterWrapper<T>;
+    type Tokenizer<T: Tokenizer> = CharacterClassFilterWrapper<T>;
 
-    fn transform<T: Tokenizer>(self, tokenizer: T) -> AlphaOnlyFilterWrapper<T> {
-        AlphaOnlyFilterWrapper(tokenizer)
+    fn transform<T: Tokenizer>(self, tokenizer: T) -> CharacterClassFilterWrapper<T> {
+        CharacterClassFilter::ALPHA_ONLY.transform(tokenizer)
+    }
+}
+
+impl TokenFilter for CharacterClassFilter {
+    type Tokenizer<T: Tokenizer> = CharacterClassFilterWrapper<T>;
+
+    fn transform<T: Tokenizer>(self, tokenizer: T) -> CharacterClassFilterWrapper<T> {
+        CharacterClassFilterWrapper {
+            filter: self,
+            inner: tokenizer,
+        }
     }
 }
 
 #[derive(Clone)]
-pub struct AlphaOnlyFilterWrapper<T>(T);
+pub struct CharacterClassFilterWrapper<T> {
+    filter: CharacterClassFilter,
+    inner: T,
+}
 
-impl<T: Tokenizer> Tokenizer for AlphaOnlyFilterWrapper<T> {
-    type TokenStream<'a> = AlphaOnlyFilterStream<T::TokenStream<'a>>;
+impl<T: Tokenizer> Tokenizer for CharacterClassFilterWrapper<T> {
+    type TokenStream<'a> = CharacterClassFilterStream<T::TokenStream<'a>>;
 
     fn token_stream<'a>(&'a mut self, text: &'a str) -> Self::TokenStream<'a> {
-        AlphaOnlyFilterStream {
-            tail: self.0.token_stream(text),
+        CharacterClassFilterStream {
+            filter: self.filter,
+            tail: self.inner.token_stream(text),
         }
     }
 }
 
-impl<T: TokenStream> TokenStream for AlphaOnlyFilterStream<T> {
+pub struct CharacterClassFilterStream<T> {
+    filter: CharacterClassFilter,
+    tail: T,
+}
+
+impl<T: TokenStream> TokenStream for CharacterClassFilterStream<T> {
     fn advance(&mut self) -> bool {
         while self.tail.advance() {
-            if self.predicate(self.tail.token()) {
+            let filter = self.filter;
+            let token = self.tail.token_mut();
+            if token.text.chars().all(|c| filter.allows(c)) {
                 return true;
             }
+            if filter.mode == FilterMode::Strip {
+                token.text.retain(|c| filter.allows(c));
+                if !token.text.is_empty() {
+                    return true;
+                }
+            }
         }
 
         false
@@ -91,9 +198,9 @@ impl<T: TokenStream> TokenStream for AlphaOnlyFilterStream<T> {
 
 #[cfg(test)]
 mod tests {
-    use crate::alpha_only_filter::AlphaOnlyFilter;
+    use crate::alpha_only_filter::{AlphaOnlyFilter, CharacterClassFilter, FilterMode};
     use crate::assert_token;
-    use tantivy::tokenizer::{SimpleTokenizer, TextAnalyzer, Token};
+    use tantivy::tokenizer::{SimpleTokenizer, TextAnalyzer, Token, TokenFilter};
     #[test]
     fn test_alphanum_only() {
         let tokens = token_stream_helper("i am a cat. 我輩は猫である。(1906)");
@@ -104,10 +211,55 @@ mod tests {
         assert_token(&tokens[3], 3, "cat", 7, 10);
     }
 
+    #[test]
+    fn test_alphanumeric_keeps_numbers_and_mixed_tokens() {
+        let tokens = token_stream_helper_with(
+            CharacterClassFilter::ALPHANUMERIC,
+            "covid19 năm 2023, g7 và 5G 🚀",
+        );
+        let texts: Vec<_> = tokens.iter().map(|token| token.text.as_str()).collect();
+        assert_eq!(texts, ["covid19", "năm", "2023", "g7", "và", "5G"]);
+    }
+
+    #[test]
+    fn test_uppercase_is_allowed() {
+        let tokens = token_stream_helper_with(CharacterClassFilter::ALPHA_ONLY, "Đà NẴNG 1906");
+        let texts: Vec<_> = tokens.iter().map(|token| token.text.as_str()).collect();
+        assert_eq!(texts, ["Đà", "NẴNG"]);
+    }
+
+    #[test]
+    fn test_strip_mode() {
+        let filter = CharacterClassFilter {
+            mode: FilterMode::Strip,
+            ..CharacterClassFilter::ALPHA_ONLY
+        };
+        let tokens = token_stream_helper_with(filter, "g7 1906 café");
+        assert_eq!(tokens.len(), 2);
+        assert_token(&tokens[0], 0, "g", 0, 2);
+        assert_token(&tokens[1], 2, "café", 8, 13);
+    }
+
+    #[test]
+    fn test_classes() {
+        let latin_only = CharacterClassFilter {
+            vietnamese: false,
+            ..CharacterClassFilter::ALPHANUMERIC
+        };
+        assert!(latin_only.allows('a'));
+        assert!(latin_only.allows('7'));
+        assert!(!latin_only.allows('đ'));
+        assert!(CharacterClassFilter::ALPHA_ONLY.allows('Ự'));
+        assert!(!CharacterClassFilter::ALPHA_ONLY.allows('ñ'));
+        assert!(!CharacterClassFilter::ALPHA_ONLY.allows('猫'));
+    }
+
     fn token_stream_helper(text: &str) -> Vec<Token> {
-        let mut a = TextAnalyzer::builder(SimpleTokenizer::default())
-            .filter(AlphaOnlyFilter)
-            .build();
+        token_stream_helper_with(AlphaOnlyFilter, text)
+    }
+
+    fn token_stream_helper_with<F: TokenFilter>(filter: F, text: &str) -> Vec<Token> {
+        let mut a = TextAnalyzer::builder(filter.transform(SimpleTokenizer::default())).build();
         let mut token_stream = a.token_stream(text);
         let mut tokens: Vec<Token> = vec![];
         let mut add_token = |token: &Token| {
@@ -1,72 +1,429 @@
-use crate::wrapper::query_wrapper;
+use crate::error::Error;
+use crate::facet::{add_facets, parse_month, FacetFilters, Facets};
+use crate::pagination::{Page, PageInfo};
+use crate::snippet::{SnippetOptions, Snippets};
+use crate::wrapper::{
+    query_wrapper, search_wrapper, QueryMode, SearchField, SearchOptions, SearchResults, Sort,
+};
 use crate::AppState;
-use axum::{extract::State, http::StatusCode, Json};
+use axum::extract::{Path, Query, State};
+use axum::Json;
 use serde::{Deserialize, Serialize};
-use sqlx::postgres::PgRow;
-use sqlx::types::chrono::{DateTime, Utc};
+use sqlx::postgres::{PgExecutor, PgPool, PgRow};
+use sqlx::types::chrono::{DateTime, TimeZone, Utc};
 use sqlx::{FromRow, Row};
+use tantivy::collector::{Count, TopDocs};
+use tantivy::query::{AllQuery, TermQuery};
+use tantivy::schema::{IndexRecordOption, Schema};
+use tantivy::{doc, Document, Score, Searcher, Term};
+
+/// Returns the article with the given id. Postgres is the source of truth when the
+/// server has a database, the index still answers for articles only imported into it.
+pub async fn get_article(
+    State(app_state): State<AppState>,
+    Path(article_id): Path<String>,
+) -> Result<Json<Article>, Error> {
+    if let Some(pool) = &app_state.pool {
+        if let Some(article) = Article::find(pool, &article_id).await? {
+            return Ok(Json(article));
+        }
+    }
+    match find_indexed_article(&app_state, &article_id)? {
+        Some(article) => Ok(Json(article)),
+        None => Err(Error::NotFound),
+    }
+}
+
+/// Looks the article up by its `id` term, the id field is not tokenized.
+pub fn find_indexed_article(app_state: &AppState, id: &str) -> tantivy::Result<Option<Article>> {
+    let schema = app_state.index().schema();
+    let searcher = app_state.reader().searcher();
+    let query = TermQuery::new(
+        Term::from_field_text(schema.get_field("id")?, id),
+        IndexRecordOption::Basic,
+    );
+    let Some((_, address)) = searcher
+        .search(&query, &TopDocs::with_limit(1))?
+        .into_iter()
+        .next()
+    else {
+        return Ok(None);
+    };
+    Ok(Article::from_doc(&schema, &searcher.doc(address)?))
+}
+
+/// Whether an article with this id is in the index.
+pub(crate) fn is_indexed(searcher: &Searcher, id: &str) -> tantivy::Result<bool> {
+    let id_field = searcher.schema().get_field("id")?;
+    let query = TermQuery::new(
+        Term::from_field_text(id_field, id),
+        IndexRecordOption::Basic,
+    );
+    Ok(searcher.search(&query, &Count)? > 0)
+}
+
+/// Request of the latest articles feed shown before anything is searched.
+#[derive(Deserialize)]
+pub struct LoadArticles {
+    offset: Option<usize>,
+    limit: Option<usize>,
+    cursor: Option<String>,
+    /// Only list articles from this domain, e.g. "dantri.com.vn".
+    source: Option<String>,
+    /// Only list articles of this category or its subcategories, e.g. "/the-thao".
+    category: Option<String>,
+    /// Only list articles created in this month, e.g. "2023-11".
+    month: Option<String>,
+}
+
+/// Facet filters of a request, rejected when the month is not like "2023-11".
+fn facet_filters(
+    source: Option<String>,
+    category: Option<String>,
+    month: Option<String>,
+) -> Result<FacetFilters, Error> {
+    if month
+        .as_deref()
+        .is_some_and(|month| parse_month(month).is_none())
+    {
+        return Err(Error::unprocessable_entity([(
+            "month",
+            "expected a year and month like 2023-11",
+        )]));
+    }
+    Ok(FacetFilters {
+        source,
+        category,
+        month,
+    })
+}
+
+/// Lists the indexed articles, newest first.
+pub async fn load_articles(
+    State(app_state): State<AppState>,
+    Query(params): Query<LoadArticles>,
+) -> Result<Json<QueryArticleResponse>, Error> {
+    let page = Page::resolve(params.offset, params.limit, params.cursor.as_deref())
+        .map_err(|e| Error::unprocessable_entity([(e.field(), e.to_string())]))?;
+    let options = SearchOptions {
+        page,
+        sort: Sort::Newest,
+        filters: facet_filters(params.source, params.category, params.month)?,
+        ..SearchOptions::default()
+    };
+    let results = search_wrapper(&app_state, Box::new(AllQuery), &options)?;
+    Ok(Json(QueryArticleResponse::new(page, results)))
+}
 
-// pub async fn get_article(
-//     State(state): State<AppState>,
-//     payload: Json<GetArticle>,
-// ) -> (StatusCode, Json<Article>) {
-//     // TODO: handle not found article id
-//     let article = sqlx::query_as::<_, Article>(r#"select * from "article" where article_url = $1"#)
-//         .bind(payload.article_id.clone())
-//         .fetch_one(&state.pool)
-//         .await;
-//     match article {
-//         Ok(article) => (StatusCode::CREATED, Json(article)),
-//         Err(_) => panic!("Not found"),
-//     }
-// }
 // Query using tantivy
 #[derive(Deserialize)]
 pub struct QueryArticle {
     query: String,
+    offset: Option<usize>,
+    limit: Option<usize>,
+    /// Opaque `next_cursor` from a previous response, takes precedence over `offset`.
+    cursor: Option<String>,
+    /// Fragment length and highlight markers of the returned snippets.
+    #[serde(default)]
+    snippets: SnippetOptions,
+    /// Only match articles created at or after this RFC 3339 timestamp.
+    from: Option<DateTime<Utc>>,
+    /// Only match articles created at or before this RFC 3339 timestamp.
+    to: Option<DateTime<Utc>>,
+    #[serde(default)]
+    sort: Sort,
+    /// Fields searched by terms without a field name, e.g. `["title"]`, all by default.
+    #[serde(default)]
+    fields: Vec<SearchField>,
+    /// "simple" searches the words literally, "advanced" (the default) accepts the query syntax
+    /// and recovers from mistakes in it, "strict" rejects a malformed query instead.
+    #[serde(default)]
+    mode: QueryMode,
+    /// Also match terms within a few typos, this is done anyway when nothing matches exactly.
+    #[serde(default)]
+    fuzzy: bool,
+    /// Only match articles from this domain, e.g. "dantri.com.vn".
+    source: Option<String>,
+    /// Only match articles of this category or its subcategories, e.g. "/the-thao".
+    category: Option<String>,
+    /// Only match articles created in this month, e.g. "2023-11".
+    month: Option<String>,
 }
+/// A single ranked article, built from the stored fields of the index.
+#[derive(Debug, Serialize)]
+pub struct SearchHit {
+    pub id: String,
+    pub title: String,
+    pub summary: String,
+    pub url: String,
+    pub source: String,
+    pub category: String,
+    pub created_time: Option<DateTime<Utc>>,
+    /// Relevance of the hit, only computed when sorting by relevance.
+    pub score: Option<Score>,
+    pub snippets: Snippets,
+}
+
+impl SearchHit {
+    pub fn from_doc(schema: &Schema, doc: &Document, score: Option<Score>) -> Self {
+        let text = |name: &str| {
+            schema
+                .get_field(name)
+                .ok()
+                .and_then(|field| doc.get_first(field))
+                .and_then(|value| value.as_text())
+                .unwrap_or_default()
+                .to_string()
+        };
+        let facet = |name: &str| {
+            schema
+                .get_field(name)
+                .ok()
+                .and_then(|field| doc.get_first(field))
+                .and_then(|value| value.as_facet())
+        };
+        SearchHit {
+            id: text("id"),
+            title: text("title"),
+            summary: text("summary"),
+            url: text("url"),
+            source: facet("source")
+                .and_then(|source| source.to_path().first().map(|source| source.to_string()))
+                .unwrap_or_default(),
+            category: facet("category")
+                .map(|category| category.to_path_string())
+                .unwrap_or_default(),
+            created_time: schema
+                .get_field("created_time")
+                .ok()
+                .and_then(|field| doc.get_first(field))
+                .and_then(|value| value.as_date())
+                .and_then(from_tantivy_date),
+            score,
+            snippets: Snippets::default(),
+        }
+    }
+}
+
 #[derive(Serialize)]
 pub struct QueryArticleResponse {
-    data: Vec<String>,
+    data: Vec<SearchHit>,
     article_count: usize,
+    page: PageInfo,
+    next_cursor: Option<String>,
+    /// Problems found in the query, which was still searched as best as possible.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<String>,
+    /// The query actually searched, when the given one could not be parsed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recovered_query: Option<String>,
+    /// Whether terms within a few typos of the query's were matched.
+    fuzzy: bool,
+    /// "Did you mean", the query with its misspelled words corrected.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    suggestion: Option<String>,
+    /// Counts of the sources, categories and months of all the matching articles.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    facets: Option<Facets>,
+}
+
+impl QueryArticleResponse {
+    fn new(page: Page, results: SearchResults) -> Self {
+        QueryArticleResponse {
+            data: results.hits,
+            article_count: results.count,
+            page: page.info(results.count),
+            next_cursor: page.next(results.count).map(|next| next.encode()),
+            warnings: results.warnings,
+            recovered_query: results.recovered_query,
+            fuzzy: results.fuzzy,
+            suggestion: results.suggestion,
+            facets: results.facets,
+        }
+    }
 }
-// TODO: allow pagination
 pub async fn query_article(
     State(app_state): State<AppState>,
-    payload: Json<QueryArticle>,
-) -> (StatusCode, Json<QueryArticleResponse>) {
-    let query = payload.query.clone();
-    let schema = app_state.index.schema();
-    let (count, articles) = query_wrapper(app_state.index, query, schema).unwrap();
-    let mut result: Vec<String> = Vec::new();
-    for article in articles {
-        result.push(article);
-    }
-    let result = QueryArticleResponse {
-        article_count: count,
-        data: result,
+    Json(payload): Json<QueryArticle>,
+) -> Result<Json<QueryArticleResponse>, Error> {
+    let page = Page::resolve(payload.offset, payload.limit, payload.cursor.as_deref())
+        .map_err(|e| Error::unprocessable_entity([(e.field(), e.to_string())]))?;
+    let options = SearchOptions {
+        page,
+        snippets: payload.snippets,
+        from: payload.from,
+        to: payload.to,
+        sort: payload.sort,
+        fields: payload.fields,
+        mode: payload.mode,
+        fuzzy: payload.fuzzy,
+        filters: facet_filters(payload.source, payload.category, payload.month)?,
+        facets: true,
     };
-    (StatusCode::CREATED, Json(result))
-}
-/// Errors that can happen when using the user repo.
-#[derive(Debug)]
-enum ArticleError {
-    #[allow(dead_code)]
-    NotFound,
-}
-#[derive(Deserialize)]
-pub struct GetArticle {
-    article_id: String,
+    let results = query_wrapper(&app_state, &payload.query, &options)?;
+    if results.count > 0 && page.offset == 0 {
+        app_state.query_log.record(&payload.query);
+    }
+    Ok(Json(QueryArticleResponse::new(page, results)))
 }
 // public fields
-#[derive(Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Article {
     pub id: String,
     pub title: String,
     pub summary: String,
     pub content: String,
     pub url: String,
-    pub timestamp: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Source of the relative urls stored by the dantri crawler.
+pub const DEFAULT_SOURCE: &str = "dantri.com.vn";
+
+/// Domain of an article url without its `www.` prefix, e.g. "vnexpress.net".
+pub fn url_source(url: &str) -> String {
+    let Some((_scheme, rest)) = url.split_once("://") else {
+        return DEFAULT_SOURCE.to_string();
+    };
+    let host = rest
+        .split(['/', '?', '#'])
+        .next()
+        .unwrap_or_default()
+        .rsplit('@')
+        .next()
+        .unwrap_or_default();
+    let host = host.split(':').next().unwrap_or_default().to_lowercase();
+    host.strip_prefix("www.")
+        .map(str::to_string)
+        .unwrap_or(host)
+}
+
+/// Directories of an article url path, e.g. "/the-thao/bong-da" for "/the-thao/bong-da/1.htm".
+/// Empty when the article sits at the root.
+pub fn url_category(url: &str) -> String {
+    let path = match url.split_once("://") {
+        Some((_scheme, rest)) => rest.find('/').map(|i| &rest[i..]).unwrap_or_default(),
+        None => url,
+    };
+    let path = path.split(['?', '#']).next().unwrap_or_default();
+    let mut segments: Vec<&str> = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+    // The last segment is the article itself.
+    segments.pop();
+    segments
+        .iter()
+        .map(|directory| format!("/{}", directory.to_lowercase()))
+        .collect()
+}
+
+/// Dates are indexed with a precision of one second.
+pub fn to_tantivy_date(date: &DateTime<Utc>) -> tantivy::DateTime {
+    tantivy::DateTime::from_timestamp_secs(date.timestamp())
+}
+
+pub fn from_tantivy_date(date: tantivy::DateTime) -> Option<DateTime<Utc>> {
+    Utc.timestamp_opt(date.into_timestamp_secs(), 0).single()
+}
+
+impl Article {
+    /// Inserts the article in the `article` table, or updates the row with the same id.
+    /// Takes a pool or a transaction.
+    pub async fn save<'e>(&self, executor: impl PgExecutor<'e>) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"insert into "article" (id, title, summary, content, url, created_time)
+               values ($1, $2, $3, $4, $5, $6)
+               on conflict (id) do update set
+                   title = excluded.title,
+                   summary = excluded.summary,
+                   content = excluded.content,
+                   url = excluded.url,
+                   created_time = excluded.created_time"#,
+        )
+        .bind(&self.id)
+        .bind(&self.title)
+        .bind(&self.summary)
+        .bind(&self.content)
+        .bind(&self.url)
+        .bind(self.timestamp)
+        .execute(executor)
+        .await?;
+        Ok(())
+    }
+
+    /// Inserts the article unless a row has the same id, returns whether it was inserted.
+    pub async fn insert(&self, pool: &PgPool) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            r#"insert into "article" (id, title, summary, content, url, created_time)
+               values ($1, $2, $3, $4, $5, $6)
+               on conflict (id) do nothing"#,
+        )
+        .bind(&self.id)
+        .bind(&self.title)
+        .bind(&self.summary)
+        .bind(&self.content)
+        .bind(&self.url)
+        .bind(self.timestamp)
+        .execute(pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Deletes the row with this id, returns whether there was one.
+    pub async fn delete(pool: &PgPool, id: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(r#"delete from "article" where id = $1"#)
+            .bind(id)
+            .execute(pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn find(pool: &PgPool, id: &str) -> Result<Option<Article>, sqlx::Error> {
+        sqlx::query_as::<_, Article>(r#"select * from "article" where id = $1"#)
+            .bind(id)
+            .fetch_optional(pool)
+            .await
+    }
+
+    /// Rebuilds an article from the stored fields of a document, `None` when the
+    /// document lacks its id or creation date.
+    pub fn from_doc(schema: &Schema, doc: &Document) -> Option<Article> {
+        let value = |name: &str| {
+            schema
+                .get_field(name)
+                .ok()
+                .and_then(|field| doc.get_first(field))
+        };
+        let text = |name: &str| {
+            value(name)
+                .and_then(|value| value.as_text())
+                .unwrap_or_default()
+                .to_string()
+        };
+        Some(Article {
+            id: value("id")?.as_text()?.to_string(),
+            title: text("title"),
+            summary: text("summary"),
+            content: text("content"),
+            url: text("url"),
+            timestamp: from_tantivy_date(value("created_time")?.as_date()?)?,
+        })
+    }
+
+    pub fn to_document(&self, schema: &Schema) -> Document {
+        let field = |name: &str| schema.get_field(name).unwrap();
+        let mut doc = doc!(
+            field("id") => self.id.clone(),
+            field("title") => self.title.clone(),
+            field("summary") => self.summary.clone(),
+            field("content") => self.content.clone(),
+            field("url") => self.url.clone(),
+            field("created_time") => to_tantivy_date(&self.timestamp),
+        );
+        add_facets(self, schema, &mut doc);
+        doc
+    }
 }
 
 impl<'r> FromRow<'r, PgRow> for Article {
@@ -77,8 +434,75 @@ impl<'r> FromRow<'r, PgRow> for Article {
             summary: row.try_get("summary").unwrap(),
             content: row.try_get("content").unwrap(),
             url: row.try_get("url").unwrap(),
-            timestamp: row.get::<DateTime<Utc>, _>("created_time").to_string(),
+            timestamp: row.try_get("created_time")?,
         };
         Ok(article)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::article::{get_article, load_articles, url_category, url_source, Article};
+    use crate::error::Error;
+    use crate::{test_article, test_state};
+    use axum::extract::{Path, Query, State};
+
+    #[tokio::test]
+    async fn test_get_article() {
+        let article = Article {
+            summary: "Người dân sơ tán".to_string(),
+            content: "Cơn bão mạnh nhất năm".to_string(),
+            ..test_article("a-1", "Bão số 9", 1)
+        };
+        let state = test_state(std::slice::from_ref(&article));
+
+        let found = get_article(State(state.clone()), Path("a-1".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(found.content, article.content);
+        assert_eq!(found.timestamp, article.timestamp);
+
+        // Only the exact id matches.
+        for id in ["a", "A-1", "missing"] {
+            let missing = get_article(State(state.clone()), Path(id.to_string())).await;
+            assert!(matches!(missing, Err(Error::NotFound)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_deep_offset_is_unprocessable() {
+        let state = test_state(&[]);
+        let query =
+            Query::try_from_uri(&"/api/articles?offset=18446744073709551615".parse().unwrap())
+                .unwrap();
+        match load_articles(State(state), query).await {
+            Err(Error::UnprocessableEntity { errors }) => assert!(errors.contains_key("offset")),
+            _ => panic!("a deep offset should be refused"),
+        }
+    }
+
+    #[test]
+    fn test_source_and_category_from_url() {
+        let cases = [
+            (
+                "/the-thao/bong-da/1.htm",
+                "dantri.com.vn",
+                "/the-thao/bong-da",
+            ),
+            (
+                "https://www.VnExpress.net:443/Kinh-Doanh/2.html?a=b",
+                "vnexpress.net",
+                "/kinh-doanh",
+            ),
+            ("http://smh.com.au/tellus.json?lectus=a/b", "smh.com.au", ""),
+            ("https://tuoitre.vn", "tuoitre.vn", ""),
+        ];
+        for (url, source, category) in cases {
+            assert_eq!(
+                (url_source(url).as_str(), url_category(url).as_str()),
+                (source, category),
+                "{url}"
+            );
+        }
+    }
+}
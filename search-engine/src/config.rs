@@ -0,0 +1,505 @@
+//! Runtime configuration of the server.
+//!
+//! Settings are layered, each layer overriding the previous one:
+//!
+//! 1. the defaults below,
+//! 2. a TOML file, `news-searcher.toml` when it exists or the one given with `--config`,
+//! 3. `NEWS_SEARCHER_*` environment variables, e.g. `NEWS_SEARCHER_BIND_ADDRESS`,
+//! 4. command line flags.
+//!
+//! ```toml
+//! bind_address = "127.0.0.1:3030"
+//! index_path = "index"
+//! allowed_origins = ["http://localhost:3000"]
+//! writer_memory = 50_000_000
+//! analyzer = "vietnamese"
+//! log_level = "search_engine=debug,tower_http=debug"
+//!
+//! [boosts]
+//! title = 3.0
+//! summary = 1.5
+//! content = 1.0
+//!
+//! [fuzzy]
+//! one_edit_from = 4
+//! two_edits_from = 8
+//! fallback = true
+//!
+//! [commit]
+//! max_pending = 1000
+//! interval_ms = 1000
+//! ```
+use crate::indexer::DEFAULT_WRITER_MEMORY;
+use crate::vietnamese::vietnamese_analyzer;
+use axum::http::HeaderValue;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use tantivy::tokenizer::TextAnalyzer;
+use tracing_subscriber::EnvFilter;
+
+/// Read when no `--config` file is given, if it exists.
+pub const DEFAULT_CONFIG_FILE: &str = "news-searcher.toml";
+/// Prefix of the environment variables overriding the configuration file.
+pub const ENV_PREFIX: &str = "NEWS_SEARCHER_";
+/// Smallest heap tantivy accepts for an `IndexWriter` thread.
+pub const MIN_WRITER_MEMORY: usize = 15_000_000;
+/// Shortest write API token accepted, so it cannot be guessed.
+pub const MIN_API_TOKEN_LENGTH: usize = 16;
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    #[error("could not read the configuration file {path}: {error}")]
+    Read {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+
+    #[error("invalid configuration file {path}: {error}")]
+    Parse {
+        path: PathBuf,
+        error: toml::de::Error,
+    },
+
+    #[error("invalid value for {name}: {message}")]
+    Invalid { name: String, message: String },
+
+    #[error("unknown environment variable {0}")]
+    UnknownVariable(String),
+}
+
+impl ConfigError {
+    fn invalid(name: impl Into<String>, message: impl ToString) -> Self {
+        ConfigError::Invalid {
+            name: name.into(),
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Text analysis of the title, summary, content and url fields.
+///
+/// Documents are analyzed when they are indexed, so an index only opens with the
+/// analyzer it was built with, see `reindex`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Analyzer {
+    /// Syllables along with their unaccented form.
+    #[default]
+    Vietnamese,
+    /// `Vietnamese` plus the bigrams of adjacent syllables.
+    VietnameseBigrams,
+}
+
+impl Analyzer {
+    /// As written in the configuration.
+    pub fn name(self) -> &'static str {
+        match self {
+            Analyzer::Vietnamese => "vietnamese",
+            Analyzer::VietnameseBigrams => "vietnamese_bigrams",
+        }
+    }
+
+    pub fn build(self) -> TextAnalyzer {
+        vietnamese_analyzer(self == Analyzer::VietnameseBigrams)
+    }
+}
+
+impl FromStr for Analyzer {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "vietnamese" => Ok(Analyzer::Vietnamese),
+            "vietnamese_bigrams" => Ok(Analyzer::VietnameseBigrams),
+            _ => Err(format!(
+                "unknown analyzer {value:?}, expected \"vietnamese\" or \"vietnamese_bigrams\""
+            )),
+        }
+    }
+}
+
+/// Weight of a match in each searchable field, title matches outrank content ones by default.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Boosts {
+    pub title: f32,
+    pub summary: f32,
+    pub content: f32,
+}
+
+impl Default for Boosts {
+    fn default() -> Self {
+        Boosts {
+            title: 3.0,
+            summary: 1.5,
+            content: 1.0,
+        }
+    }
+}
+
+/// Typo tolerance, terms shorter than `one_edit_from` characters must match exactly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FuzzyConfig {
+    pub one_edit_from: usize,
+    pub two_edits_from: usize,
+    /// Searches again with typo tolerance when a query has no hits.
+    pub fallback: bool,
+}
+
+impl Default for FuzzyConfig {
+    fn default() -> Self {
+        FuzzyConfig {
+            one_edit_from: 4,
+            two_edits_from: 8,
+            fallback: true,
+        }
+    }
+}
+
+/// When the index writer of the server commits: once `max_pending` operations are
+/// waiting, or `interval_ms` after the first of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CommitConfig {
+    pub max_pending: usize,
+    pub interval_ms: u64,
+}
+
+impl Default for CommitConfig {
+    fn default() -> Self {
+        CommitConfig {
+            max_pending: 1000,
+            interval_ms: 1000,
+        }
+    }
+}
+
+impl CommitConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind_address: SocketAddr,
+    pub index_path: PathBuf,
+    /// The server runs index-only when no database is configured.
+    /// `DATABASE_URL` is read as well, as it is by the sqlx tooling.
+    pub database_url: Option<String>,
+    /// Origins allowed to call the API from a browser.
+    pub allowed_origins: Vec<String>,
+    /// Heap of the `IndexWriter`, in bytes.
+    pub writer_memory: usize,
+    pub analyzer: Analyzer,
+    /// Only read from the configuration file, as a `[boosts]` table.
+    pub boosts: Boosts,
+    /// Only read from the configuration file, as a `[fuzzy]` table.
+    pub fuzzy: FuzzyConfig,
+    /// Only read from the configuration file, as a `[commit]` table.
+    pub commit: CommitConfig,
+    /// A `tracing_subscriber::EnvFilter` directive, e.g. "search_engine=debug".
+    pub log_level: String,
+    /// Bearer token of the write API, which is disabled without one.
+    pub api_token: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind_address: SocketAddr::from(([127, 0, 0, 1], 3030)),
+            index_path: PathBuf::from("index"),
+            database_url: None,
+            allowed_origins: vec!["http://localhost:3000".to_string()],
+            writer_memory: DEFAULT_WRITER_MEMORY,
+            analyzer: Analyzer::default(),
+            boosts: Boosts::default(),
+            fuzzy: FuzzyConfig::default(),
+            commit: CommitConfig::default(),
+            log_level: "search_engine=debug,tower_http=debug".to_string(),
+            api_token: None,
+        }
+    }
+}
+
+impl Config {
+    /// Reads `path`, or `DEFAULT_CONFIG_FILE` when it exists, then applies the
+    /// environment of the process. The result still has to be validated.
+    pub fn load(path: Option<&Path>) -> Result<Config, ConfigError> {
+        let mut config = match path {
+            Some(path) => Config::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Config::from_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => Config::default(),
+        };
+        config.apply_env(std::env::vars())?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(|error| ConfigError::Read {
+            path: path.to_path_buf(),
+            error,
+        })?;
+        toml::from_str(&text).map_err(|error| ConfigError::Parse {
+            path: path.to_path_buf(),
+            error,
+        })
+    }
+
+    /// Overrides the settings with the `NEWS_SEARCHER_*` variables among `vars`.
+    /// `NEWS_SEARCHER_ALLOWED_ORIGINS` is a comma separated list.
+    pub fn apply_env(
+        &mut self,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<(), ConfigError> {
+        let mut database_url = None;
+        for (name, value) in vars {
+            if name == "DATABASE_URL" {
+                database_url = database_url.or(Some(value));
+                continue;
+            }
+            let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            match key {
+                "BIND_ADDRESS" => {
+                    self.bind_address = value.parse().map_err(|e| ConfigError::invalid(&name, e))?
+                }
+                "INDEX_PATH" => self.index_path = PathBuf::from(value),
+                "DATABASE_URL" => database_url = Some(value),
+                "ALLOWED_ORIGINS" => {
+                    self.allowed_origins = value
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|origin| !origin.is_empty())
+                        .map(str::to_string)
+                        .collect()
+                }
+                "WRITER_MEMORY" => {
+                    self.writer_memory =
+                        value.parse().map_err(|e| ConfigError::invalid(&name, e))?
+                }
+                "ANALYZER" => {
+                    self.analyzer = value.parse().map_err(|e| ConfigError::invalid(&name, e))?
+                }
+                "LOG_LEVEL" => self.log_level = value,
+                "API_TOKEN" => self.api_token = Some(value).filter(|token| !token.is_empty()),
+                // Read by the command line parser.
+                "CONFIG" => {}
+                _ => return Err(ConfigError::UnknownVariable(name)),
+            }
+        }
+        // `NEWS_SEARCHER_DATABASE_URL` wins over `DATABASE_URL`.
+        if let Some(database_url) = database_url {
+            self.database_url = Some(database_url).filter(|url| !url.is_empty());
+        }
+        Ok(())
+    }
+
+    /// Checks every setting, so a misconfigured server fails at startup.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.index_path.as_os_str().is_empty() {
+            return Err(ConfigError::invalid("index_path", "must not be empty"));
+        }
+        if let Some(url) = &self.database_url {
+            if !url.starts_with("postgres://") && !url.starts_with("postgresql://") {
+                return Err(ConfigError::invalid(
+                    "database_url",
+                    "expected a postgres:// url",
+                ));
+            }
+        }
+        for origin in &self.allowed_origins {
+            if !origin.starts_with("http://") && !origin.starts_with("https://") {
+                return Err(ConfigError::invalid(
+                    "allowed_origins",
+                    format!("{origin:?} is not an http(s) origin"),
+                ));
+            }
+            HeaderValue::from_str(origin)
+                .map_err(|e| ConfigError::invalid("allowed_origins", format!("{origin:?}: {e}")))?;
+        }
+        if self.writer_memory < MIN_WRITER_MEMORY {
+            return Err(ConfigError::invalid(
+                "writer_memory",
+                format!("must be at least {MIN_WRITER_MEMORY} bytes"),
+            ));
+        }
+        let boosts = self.boosts;
+        for (name, boost) in [
+            ("boosts.title", boosts.title),
+            ("boosts.summary", boosts.summary),
+            ("boosts.content", boosts.content),
+        ] {
+            if !boost.is_finite() || boost <= 0.0 {
+                return Err(ConfigError::invalid(name, "must be a positive number"));
+            }
+        }
+        if self.fuzzy.one_edit_from > self.fuzzy.two_edits_from {
+            return Err(ConfigError::invalid(
+                "fuzzy.two_edits_from",
+                "must not be smaller than fuzzy.one_edit_from",
+            ));
+        }
+        if self.commit.max_pending == 0 {
+            return Err(ConfigError::invalid(
+                "commit.max_pending",
+                "must be at least 1",
+            ));
+        }
+        if self.commit.interval_ms == 0 {
+            return Err(ConfigError::invalid(
+                "commit.interval_ms",
+                "must be at least 1",
+            ));
+        }
+        if let Some(token) = &self.api_token {
+            if token.len() < MIN_API_TOKEN_LENGTH {
+                return Err(ConfigError::invalid(
+                    "api_token",
+                    format!("must be at least {MIN_API_TOKEN_LENGTH} characters"),
+                ));
+            }
+        }
+        self.log_filter()?;
+        Ok(())
+    }
+
+    pub fn log_filter(&self) -> Result<EnvFilter, ConfigError> {
+        EnvFilter::try_new(&self.log_level).map_err(|e| ConfigError::invalid("log_level", e))
+    }
+
+    pub fn allowed_origins(&self) -> Vec<HeaderValue> {
+        self.allowed_origins
+            .iter()
+            .filter_map(|origin| HeaderValue::from_str(origin).ok())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Analyzer, Boosts, CommitConfig, Config, ConfigError, FuzzyConfig};
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_file_then_env() {
+        let mut config: Config = toml::from_str(
+            r#"
+            bind_address = "0.0.0.0:8080"
+            analyzer = "vietnamese_bigrams"
+            writer_memory = 100_000_000
+
+            [boosts]
+            title = 5.0
+
+            [commit]
+            max_pending = 10
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.index_path, Config::default().index_path);
+        config
+            .apply_env(vars(&[
+                ("NEWS_SEARCHER_WRITER_MEMORY", "20000000"),
+                (
+                    "NEWS_SEARCHER_DATABASE_URL",
+                    "postgres://localhost/articles",
+                ),
+                ("DATABASE_URL", "postgres://elsewhere/articles"),
+                (
+                    "NEWS_SEARCHER_ALLOWED_ORIGINS",
+                    "http://localhost:3000, https://news.example",
+                ),
+                ("HOME", "/root"),
+            ]))
+            .unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.bind_address.port(), 8080);
+        assert_eq!(config.analyzer, Analyzer::VietnameseBigrams);
+        assert_eq!(config.writer_memory, 20_000_000);
+        assert_eq!(config.boosts.title, 5.0);
+        assert_eq!(config.boosts.content, Boosts::default().content);
+        assert_eq!(config.commit.max_pending, 10);
+        assert_eq!(
+            config.commit.interval_ms,
+            CommitConfig::default().interval_ms
+        );
+        assert_eq!(
+            config.database_url.as_deref(),
+            Some("postgres://localhost/articles")
+        );
+        assert_eq!(config.allowed_origins().len(), 2);
+    }
+
+    #[test]
+    fn test_invalid_settings() {
+        assert!(toml::from_str::<Config>("bind_adress = \"0.0.0.0:80\"").is_err());
+
+        let mut config = Config::default();
+        let error = config
+            .apply_env(vars(&[("NEWS_SEARCHER_ANALYZER", "english")]))
+            .unwrap_err();
+        assert!(
+            matches!(&error, ConfigError::Invalid { name, .. } if name == "NEWS_SEARCHER_ANALYZER")
+        );
+        let error = config
+            .apply_env(vars(&[("NEWS_SEARCHER_INDEX", "index")]))
+            .unwrap_err();
+        assert!(matches!(error, ConfigError::UnknownVariable(_)));
+
+        for invalid in [
+            Config {
+                writer_memory: 1_000,
+                ..Config::default()
+            },
+            Config {
+                allowed_origins: vec!["localhost:3000".to_string()],
+                ..Config::default()
+            },
+            Config {
+                database_url: Some("mysql://localhost".to_string()),
+                ..Config::default()
+            },
+            Config {
+                fuzzy: FuzzyConfig {
+                    one_edit_from: 9,
+                    ..FuzzyConfig::default()
+                },
+                ..Config::default()
+            },
+            Config {
+                boosts: Boosts {
+                    summary: -1.0,
+                    ..Boosts::default()
+                },
+                ..Config::default()
+            },
+            Config {
+                commit: CommitConfig {
+                    interval_ms: 0,
+                    ..CommitConfig::default()
+                },
+                ..Config::default()
+            },
+            Config {
+                api_token: Some("secret".to_string()),
+                ..Config::default()
+            },
+        ] {
+            assert!(invalid.validate().is_err(), "{invalid:?}");
+        }
+    }
+}
@@ -0,0 +1,433 @@
+//! Crawler for dantri.com.vn, replacing the Scrapy project the pipeline used to rely on.
+//!
+//! Section pages are fetched first and the article links they hold are followed, as
+//! well as the related articles linked from each article, until enough articles were
+//! crawled. An article is recognized by the id closing its url, e.g.
+//! `/the-thao/doi-tuyen-20231101100000000.htm`, which also becomes its `id`: urls
+//! are deduplicated once their query and fragment are dropped, pages whose article
+//! is already indexed are not fetched again, and crawling an article twice replaces
+//! the same document.
+use crate::article::{is_indexed, Article, DEFAULT_SOURCE};
+use crate::sink::ArticleSink;
+use reqwest::{Client, Url};
+use scraper::{Html, Selector};
+use sqlx::postgres::PgPool;
+use sqlx::types::chrono::{DateTime, FixedOffset, NaiveDateTime, Utc};
+use std::collections::{HashSet, VecDeque};
+use std::time::Duration;
+use tantivy::Index;
+
+/// Section pages crawled when none are given.
+pub const DEFAULT_START_URLS: [&str; 6] = [
+    "https://dantri.com.vn/tin-moi-nhat.htm",
+    "https://dantri.com.vn/xa-hoi.htm",
+    "https://dantri.com.vn/the-gioi.htm",
+    "https://dantri.com.vn/kinh-doanh.htm",
+    "https://dantri.com.vn/the-thao.htm",
+    "https://dantri.com.vn/giai-tri.htm",
+];
+pub const USER_AGENT: &str = concat!("news-searcher/", env!("CARGO_PKG_VERSION"));
+/// Articles written to the index between two commits.
+const COMMIT_EVERY: usize = 100;
+/// Offset of the times shown by dantri.com.vn, Vietnam has no daylight saving time.
+const VIETNAM_OFFSET_SECS: i32 = 7 * 3600;
+
+#[derive(Debug, Clone)]
+pub struct CrawlOptions {
+    pub start_urls: Vec<Url>,
+    /// Articles crawled before stopping.
+    pub max_articles: usize,
+    /// Wait between two requests, so the site is not hammered.
+    pub delay: Duration,
+}
+
+/// A page that could not be fetched or parsed.
+#[derive(Debug)]
+pub struct FailedPage {
+    pub url: Url,
+    pub reason: String,
+}
+
+#[derive(Debug, Default)]
+pub struct CrawlReport {
+    pub crawled: usize,
+    /// Article links not fetched as their article is already indexed.
+    pub skipped: usize,
+    pub failed: Vec<FailedPage>,
+}
+
+/// HTTP client identifying the crawler.
+pub fn client() -> reqwest::Result<Client> {
+    Client::builder()
+        .user_agent(USER_AGENT)
+        .timeout(Duration::from_secs(30))
+        .build()
+}
+
+/// The url without its query and fragment, which only track visits or scroll.
+fn normalize(mut url: Url) -> Url {
+    url.set_query(None);
+    url.set_fragment(None);
+    url
+}
+
+/// Id closing the url of an article, e.g. "20231101100000000" for
+/// `/the-thao/doi-tuyen-20231101100000000.htm`.
+pub fn article_id(url: &Url) -> Option<&str> {
+    let name = url.path_segments()?.next_back()?;
+    let (_slug, id) = name.strip_suffix(".htm")?.rsplit_once('-')?;
+    (id.len() >= 8 && id.bytes().all(|b| b.is_ascii_digit())).then_some(id)
+}
+
+/// Url stored for an article, only its path on dantri.com.vn like the urls of the
+/// Scrapy crawler, as the UI links to them on the site.
+pub fn stored_url(url: &Url) -> String {
+    match url.host_str() {
+        Some(host) if host.trim_start_matches("www.") == DEFAULT_SOURCE => url.path().to_string(),
+        _ => url.to_string(),
+    }
+}
+
+/// Times are either RFC 3339 or local to Vietnam, e.g. `2023-11-01 17:00`.
+pub fn parse_time(value: &str) -> Option<DateTime<Utc>> {
+    let value = value.trim();
+    if let Ok(date) = DateTime::parse_from_rfc3339(value) {
+        return Some(date.with_timezone(&Utc));
+    }
+    let offset = FixedOffset::east_opt(VIETNAM_OFFSET_SECS)?;
+    ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M:%S"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+        .and_then(|date| date.and_local_timezone(offset).single())
+        .map(|date| date.with_timezone(&Utc))
+}
+
+fn selector(selector: &str) -> Selector {
+    Selector::parse(selector).expect("valid selector")
+}
+
+/// Text of the first element matching one of `selectors`, with its whitespace collapsed.
+fn select_text(html: &Html, selectors: &[&str]) -> Option<String> {
+    selectors.iter().find_map(|s| {
+        let element = html.select(&selector(s)).next()?;
+        let text = collapse_whitespace(&element.text().collect::<String>());
+        (!text.is_empty()).then_some(text)
+    })
+}
+
+/// Attribute of the first element matching one of `selectors` that has it.
+fn select_attr(html: &Html, selectors: &[&str], attr: &str) -> Option<String> {
+    selectors.iter().find_map(|s| {
+        let value = html
+            .select(&selector(s))
+            .find_map(|element| element.value().attr(attr))?;
+        let value = collapse_whitespace(value);
+        (!value.is_empty()).then_some(value)
+    })
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Links of a page to articles of the same site, in order and without duplicates.
+pub fn article_links(html: &Html, page_url: &Url) -> Vec<Url> {
+    let mut links = Vec::new();
+    for anchor in html.select(&selector("a[href]")) {
+        let Some(url) = anchor
+            .value()
+            .attr("href")
+            .and_then(|href| page_url.join(href).ok())
+        else {
+            continue;
+        };
+        let url = normalize(url);
+        if url.host_str() == page_url.host_str()
+            && article_id(&url).is_some()
+            && !links.contains(&url)
+        {
+            links.push(url);
+        }
+    }
+    links
+}
+
+/// Reads an article page, its canonical url wins over the url it was fetched from.
+pub fn parse_article(html: &Html, page_url: &Url) -> Result<Article, String> {
+    let url = select_attr(html, &["link[rel=canonical]"], "href")
+        .and_then(|href| page_url.join(&href).ok())
+        .map(normalize)
+        .unwrap_or_else(|| normalize(page_url.clone()));
+    let id = article_id(&url)
+        .ok_or_else(|| format!("{url} is not an article url"))?
+        .to_string();
+    let title = select_text(html, &["h1.title-page", "h1"])
+        .or_else(|| select_attr(html, &["meta[property='og:title']"], "content"))
+        .ok_or("missing title")?;
+    let summary = select_text(html, &[".singular-sapo"])
+        .or_else(|| {
+            select_attr(
+                html,
+                &["meta[name=description]", "meta[property='og:description']"],
+                "content",
+            )
+        })
+        .unwrap_or_default();
+    // Paragraphs nested in figures are image captions.
+    let content = [".singular-content > p", "article p"]
+        .iter()
+        .map(|s| {
+            html.select(&selector(s))
+                .map(|p| collapse_whitespace(&p.text().collect::<String>()))
+                .filter(|p| !p.is_empty())
+                .collect::<Vec<_>>()
+        })
+        .find(|paragraphs| !paragraphs.is_empty())
+        .unwrap_or_default()
+        .join("\n");
+    let timestamp = select_attr(
+        html,
+        &["meta[property='article:published_time']"],
+        "content",
+    )
+    .or_else(|| select_attr(html, &["time[datetime]"], "datetime"))
+    .ok_or("missing publish time")?;
+    let timestamp =
+        parse_time(&timestamp).ok_or_else(|| format!("invalid publish time {timestamp:?}"))?;
+    Ok(Article {
+        id,
+        title,
+        summary,
+        content,
+        url: stored_url(&url),
+        timestamp,
+    })
+}
+
+/// What was found on a fetched page. `article` is only set for article urls.
+struct Page {
+    article: Option<Result<Article, String>>,
+    links: Vec<Url>,
+}
+
+impl Page {
+    fn parse(body: &str, url: &Url) -> Page {
+        let html = Html::parse_document(body);
+        Page {
+            article: article_id(url).map(|_| parse_article(&html, url)),
+            links: article_links(&html, url),
+        }
+    }
+}
+
+async fn fetch(client: &Client, url: &Url) -> reqwest::Result<String> {
+    client
+        .get(url.clone())
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await
+}
+
+/// Crawls articles into `sink`, and into Postgres when a pool is given. Pages whose
+/// article is in `index` are skipped. Pages that cannot be fetched or parsed are
+/// reported, failing to store an article stops the crawl.
+pub async fn crawl(
+    client: &Client,
+    options: &CrawlOptions,
+    index: &Index,
+    pool: Option<&PgPool>,
+    mut sink: ArticleSink,
+) -> anyhow::Result<CrawlReport> {
+    let searcher = index.reader()?.searcher();
+    let mut report = CrawlReport::default();
+    let mut queue: VecDeque<Url> = options.start_urls.iter().cloned().map(normalize).collect();
+    let mut seen: HashSet<Url> = queue.iter().cloned().collect();
+    let mut crawled_ids: HashSet<String> = HashSet::new();
+    let mut first_request = true;
+    while let Some(url) = queue.pop_front() {
+        if report.crawled >= options.max_articles {
+            break;
+        }
+        if let Some(id) = article_id(&url) {
+            if is_indexed(&searcher, id)? {
+                report.skipped += 1;
+                continue;
+            }
+        }
+        if !first_request {
+            tokio::time::sleep(options.delay).await;
+        }
+        first_request = false;
+        let page = match fetch(client, &url).await {
+            Ok(body) => Page::parse(&body, &url),
+            Err(e) => {
+                tracing::warn!("failed to fetch {}: {}", url, e);
+                report.failed.push(FailedPage {
+                    url,
+                    reason: e.to_string(),
+                });
+                continue;
+            }
+        };
+        for link in page.links {
+            if seen.insert(link.clone()) {
+                queue.push_back(link);
+            }
+        }
+        let article = match page.article {
+            None => continue,
+            Some(Ok(article)) => article,
+            Some(Err(reason)) => {
+                tracing::warn!("failed to parse {}: {}", url, reason);
+                report.failed.push(FailedPage { url, reason });
+                continue;
+            }
+        };
+        // Another url may have led to the same canonical article.
+        if !crawled_ids.insert(article.id.clone()) {
+            continue;
+        }
+        if let Some(pool) = pool {
+            article.save(pool).await?;
+        }
+        sink.upsert(&article).await?;
+        report.crawled += 1;
+        tracing::info!("crawled {}", url);
+        if report.crawled % COMMIT_EVERY == 0 {
+            sink.commit().await?;
+        }
+    }
+    sink.finish().await?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crawler::{article_links, client, crawl, parse_article, CrawlOptions};
+    use crate::{test_index, test_sink};
+    use axum::response::Html as HtmlResponse;
+    use axum::routing::get;
+    use axum::Router;
+    use reqwest::Url;
+    use scraper::Html;
+    use std::time::Duration;
+
+    const SECTION: &str = include_str!("../fixtures/dantri/the-thao.html");
+    const ARTICLE: &str = include_str!("../fixtures/dantri/doi-tuyen-viet-nam-thang-dam.html");
+    const BARE_ARTICLE: &str =
+        include_str!("../fixtures/dantri/hlv-troussier-noi-gi-sau-tran-dau.html");
+    const BROKEN_ARTICLE: &str = include_str!("../fixtures/dantri/trang-khong-co-tieu-de.html");
+
+    /// Serves the fixtures like dantri.com.vn would, returns the url of the section page.
+    fn stand_in() -> Url {
+        let pages = [
+            ("/the-thao.htm", SECTION),
+            (
+                "/the-thao/doi-tuyen-viet-nam-thang-dam-20231101100000000.htm",
+                ARTICLE,
+            ),
+            (
+                "/the-thao/bong-da/hlv-troussier-noi-gi-sau-tran-dau-20231102083000000.htm",
+                BARE_ARTICLE,
+            ),
+            (
+                "/the-thao/trang-khong-co-tieu-de-20231103000000000.htm",
+                BROKEN_ARTICLE,
+            ),
+        ];
+        let mut router = Router::new();
+        for (path, html) in pages {
+            router = router.route(path, get(move || async move { HtmlResponse(html) }));
+        }
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(router.into_make_service());
+        tokio::spawn(server);
+        Url::parse(&format!("http://{address}/the-thao.htm")).unwrap()
+    }
+
+    #[test]
+    fn test_article_links() {
+        let page_url = Url::parse("https://dantri.com.vn/the-thao.htm").unwrap();
+        let links = article_links(&Html::parse_document(SECTION), &page_url);
+        let paths: Vec<_> = links.iter().map(|link| link.path()).collect();
+        assert_eq!(
+            paths,
+            [
+                "/the-thao/doi-tuyen-viet-nam-thang-dam-20231101100000000.htm",
+                "/the-thao/bong-da/hlv-troussier-noi-gi-sau-tran-dau-20231102083000000.htm",
+                "/the-thao/trang-khong-co-tieu-de-20231103000000000.htm",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_article() {
+        let page_url = Url::parse(
+            "http://127.0.0.1/the-thao/doi-tuyen-viet-nam-thang-dam-20231101100000000.htm?a=b",
+        )
+        .unwrap();
+        let article = parse_article(&Html::parse_document(ARTICLE), &page_url).unwrap();
+        assert_eq!(article.id, "20231101100000000");
+        assert_eq!(article.title, "Đội tuyển Việt Nam thắng đậm");
+        assert_eq!(
+            article.summary,
+            "(Dân trí) - Đội tuyển Việt Nam thắng đậm 4-0 trong trận giao hữu tối qua."
+        );
+        assert_eq!(
+            article.content,
+            "Trên sân Mỹ Đình, đội tuyển Việt Nam đã có chiến thắng thuyết phục.\n\
+             Bàn thắng mở tỷ số đến ở phút thứ 12."
+        );
+        assert_eq!(
+            article.url,
+            "/the-thao/doi-tuyen-viet-nam-thang-dam-20231101100000000.htm"
+        );
+        assert_eq!(article.timestamp.to_rfc3339(), "2023-11-01T10:00:00+00:00");
+
+        // Without meta tags nor canonical link, the url is kept whole off dantri.com.vn.
+        let article = parse_article(&Html::parse_document(BARE_ARTICLE), &page_url).unwrap();
+        assert_eq!(article.title, "HLV Troussier nói gì sau trận đấu");
+        assert_eq!(article.timestamp.to_rfc3339(), "2023-11-02T01:30:00+00:00");
+        assert_eq!(
+            article.url,
+            "http://127.0.0.1/the-thao/doi-tuyen-viet-nam-thang-dam-20231101100000000.htm"
+        );
+
+        let error = parse_article(&Html::parse_document(BROKEN_ARTICLE), &page_url).unwrap_err();
+        assert_eq!(error, "missing title");
+    }
+
+    #[tokio::test]
+    async fn test_crawl_from_stand_in() {
+        let index = test_index(&[]);
+        let mut options = CrawlOptions {
+            start_urls: vec![stand_in()],
+            max_articles: 1,
+            delay: Duration::ZERO,
+        };
+        let client = client().unwrap();
+        let report = crawl(&client, &options, &index, None, test_sink(&index))
+            .await
+            .unwrap();
+        assert_eq!(report.crawled, 1);
+
+        options.max_articles = 10;
+        let report = crawl(&client, &options, &index, None, test_sink(&index))
+            .await
+            .unwrap();
+        assert_eq!((report.crawled, report.skipped), (1, 1));
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].reason, "missing title");
+
+        let report = crawl(&client, &options, &index, None, test_sink(&index))
+            .await
+            .unwrap();
+        assert_eq!((report.crawled, report.skipped), (0, 2));
+        assert_eq!(index.reader().unwrap().searcher().num_docs(), 2);
+    }
+}
@@ -0,0 +1,141 @@
+//! Optional Postgres backend.
+//!
+//! When a database url is configured the server connects on startup, applies
+//! the migrations embedded from `migrations/` and treats the `article` table as
+//! the source of truth, the index being rebuilt from it. Without a database the
+//! server runs index-only.
+use sqlx::migrate::Migrator;
+use sqlx::postgres::{PgPool, PgPoolOptions};
+use std::time::Duration;
+
+/// The migrations under `migrations/`, embedded at compile time.
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+pub async fn connect(database_url: &str) -> Result<PgPool, sqlx::Error> {
+    PgPoolOptions::new()
+        .max_connections(5)
+        .acquire_timeout(Duration::from_secs(3))
+        .connect(database_url)
+        .await
+}
+
+/// Connects and brings the schema up to date.
+pub async fn connect_and_migrate(database_url: &str) -> anyhow::Result<PgPool> {
+    let pool = connect(database_url).await?;
+    MIGRATOR.run(&pool).await?;
+    Ok(pool)
+}
+
+/// Checks that the database answers a trivial query.
+pub async fn ping(pool: &PgPool) -> Result<(), sqlx::Error> {
+    sqlx::query("select 1").execute(pool).await?;
+    Ok(())
+}
+
+/// A throwaway database created from `TEST_DATABASE_URL`, tests are skipped when
+/// it is not set, e.g. `TEST_DATABASE_URL=postgres://postgres@localhost:5432/postgres`.
+#[cfg(test)]
+pub(crate) struct TestDatabase {
+    admin: PgPool,
+    name: String,
+    pub pool: PgPool,
+}
+
+#[cfg(test)]
+impl TestDatabase {
+    pub async fn create() -> Option<TestDatabase> {
+        let Ok(url) = std::env::var("TEST_DATABASE_URL") else {
+            eprintln!("TEST_DATABASE_URL is not set, skipping");
+            return None;
+        };
+        let admin = connect(&url).await.unwrap();
+        let name = format!(
+            "news_searcher_test_{}",
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        );
+        // Articles are Vietnamese and `1_setup.sql` creates an ICU collation, both need UTF-8.
+        sqlx::query(&format!(
+            "create database {name} template template0 encoding 'UTF8'"
+        ))
+        .execute(&admin)
+        .await
+        .unwrap();
+        let base = url.rsplit_once('/').map(|(base, _)| base).unwrap();
+        let pool = connect_and_migrate(&format!("{base}/{name}"))
+            .await
+            .unwrap();
+        Some(TestDatabase { admin, name, pool })
+    }
+
+    pub async fn destroy(self) {
+        self.pool.close().await;
+        // Backends of closed connections exit asynchronously, do not wait for them.
+        sqlx::query(&format!("drop database {} with (force)", self.name))
+            .execute(&self.admin)
+            .await
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::article::{find_indexed_article, get_article, Article};
+    use crate::db::{ping, TestDatabase, MIGRATOR};
+    use crate::indexer::sync_from_postgres;
+    use crate::{test_article, test_sink, test_state};
+    use axum::extract::{Path, State};
+
+    #[tokio::test]
+    async fn test_postgres_is_the_source_of_truth() {
+        let Some(db) = TestDatabase::create().await else {
+            return;
+        };
+        ping(&db.pool).await.unwrap();
+        // Migrations are only applied once.
+        MIGRATOR.run(&db.pool).await.unwrap();
+
+        let mut article = Article {
+            summary: "Người dân sơ tán".to_string(),
+            content: "Cơn bão mạnh nhất năm".to_string(),
+            ..test_article("1", "Bão số 9", 1)
+        };
+        article.save(&db.pool).await.unwrap();
+        let state = test_state(&[]).with_pool(db.pool.clone());
+        assert_eq!(
+            sync_from_postgres(&db.pool, &state.index(), test_sink(&state.index()))
+                .await
+                .unwrap(),
+            1
+        );
+
+        article.content = "Bão đã tan".to_string();
+        article.save(&db.pool).await.unwrap();
+        let found = get_article(State(state.clone()), Path("1".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(found.content, "Bão đã tan");
+
+        // A row committed after the sync by a transaction that started before it.
+        sqlx::query(
+            r#"insert into "article" (id, title, summary, content, created_time, url, updated_at)
+               select '0', 'Mưa lớn', '', '', created_time, '/xa-hoi/0.htm',
+                      updated_at - interval '1 second'
+               from "article" where id = '1'"#,
+        )
+        .execute(&db.pool)
+        .await
+        .unwrap();
+        let indexed = sync_from_postgres(&db.pool, &state.index(), test_sink(&state.index()))
+            .await
+            .unwrap();
+        assert_eq!(indexed, 2);
+        state.reload().unwrap();
+        let found = find_indexed_article(&state, "0").unwrap().unwrap();
+        assert_eq!(found.title, "Mưa lớn");
+
+        db.destroy().await;
+    }
+}
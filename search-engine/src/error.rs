@@ -1,11 +1,11 @@
-use axum::body::{Bytes, Full, HttpBody};
 use axum::http::header::WWW_AUTHENTICATE;
-use axum::http::{HeaderMap, HeaderValue, Response, StatusCode};
-use axum::response::IntoResponse;
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
 use axum::Json;
 use sqlx::error::DatabaseError;
 use std::borrow::Cow;
 use std::collections::HashMap;
+use tantivy::query::QueryParserError;
 
 /// A common error type that can be used throughout the API.
 ///
@@ -43,6 +43,14 @@ pub enum Error {
         errors: HashMap<Cow<'static, str>, Vec<Cow<'static, str>>>,
     },
 
+    /// Return `400 Bad Request` when the search query can not be parsed.
+    ///
+    /// The body is a JSON object carrying the kind of parse error reported by tantivy
+    /// along with its message, so the frontend can point the user at the problem
+    /// instead of showing a generic failure.
+    #[error("invalid search query: {0}")]
+    BadQuery(#[from] QueryParserError),
+
     /// Automatically return `500 Internal Server Error` on a `sqlx::Error`.
     ///
     /// Via the generated `From<sqlx::Error> for Error` impl,
@@ -61,6 +69,12 @@ pub enum Error {
     #[error("an error occurred with the database")]
     Sqlx(#[from] sqlx::Error),
 
+    /// Return `500 Internal Server Error` on a `tantivy::TantivyError`.
+    ///
+    /// Like with `Error::Sqlx`, the actual error message is not returned to the client.
+    #[error("an error occurred with the search index")]
+    Tantivy(#[from] tantivy::TantivyError),
+
     /// Return `500 Internal Server Error` on a `anyhow::Error`.
     ///
     /// `anyhow::Error` is used in a few places to capture context and backtraces
@@ -106,7 +120,8 @@ impl Error {
             Self::Forbidden => StatusCode::FORBIDDEN,
             Self::NotFound => StatusCode::NOT_FOUND,
             Self::UnprocessableEntity { .. } => StatusCode::UNPROCESSABLE_ENTITY,
-            Self::Sqlx(_) | Self::Anyhow(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::BadQuery(_) => StatusCode::BAD_REQUEST,
+            Self::Sqlx(_) | Self::Tantivy(_) | Self::Anyhow(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 }
@@ -117,10 +132,7 @@ impl Error {
 /// By default, the generated `Display` impl is used to return a plaintext error message
 /// to the client.
 impl IntoResponse for Error {
-    type Body = Full<Bytes>;
-    type BodyError = <Full<Bytes> as HttpBody>::Error;
-
-    fn into_response(self) -> Response<Self::Body> {
+    fn into_response(self) -> Response {
         match self {
             Self::UnprocessableEntity { errors } => {
                 #[derive(serde::Serialize)]
@@ -130,6 +142,27 @@ impl IntoResponse for Error {
 
                 return (StatusCode::UNPROCESSABLE_ENTITY, Json(Errors { errors })).into_response();
             }
+            Self::BadQuery(ref e) => {
+                #[derive(serde::Serialize)]
+                struct BadQuery {
+                    error: &'static str,
+                    kind: String,
+                    message: String,
+                }
+
+                // The variant name of the parse error, e.g. `SyntaxError` or `FieldDoesNotExist`.
+                let kind = format!("{e:?}")
+                    .split(|c: char| !c.is_alphanumeric())
+                    .next()
+                    .unwrap_or_default()
+                    .to_string();
+                let body = BadQuery {
+                    error: "invalid search query",
+                    kind,
+                    message: e.to_string(),
+                };
+                return (self.status_code(), Json(body)).into_response();
+            }
             Self::Unauthorized => {
                 return (
                     self.status_code(),
@@ -151,15 +184,15 @@ impl IntoResponse for Error {
             }
 
             Self::Sqlx(ref e) => {
-                // TODO: we probably want to use `tracing` instead
-                // so that this gets linked to the HTTP request by `TraceLayer`.
-                log::error!("SQLx error: {:?}", e);
+                tracing::error!("SQLx error: {:?}", e);
+            }
+
+            Self::Tantivy(ref e) => {
+                tracing::error!("Tantivy error: {:?}", e);
             }
 
             Self::Anyhow(ref e) => {
-                // TODO: we probably want to use `tracing` instead
-                // so that this gets linked to the HTTP request by `TraceLayer`.
-                log::error!("Generic error: {:?}", e);
+                tracing::error!("Generic error: {:?}", e);
             }
 
             // Other errors get mapped normally.
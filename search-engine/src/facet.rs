@@ -0,0 +1,198 @@
+//! Facets narrowing a search to a news source, a category or a month.
+//!
+//! Each article is indexed with three facets derived when it is added: its source
+//! domain (`/dantri.com.vn`), the directories of its url path (`/the-thao/bong-da`)
+//! and the month it was created in, in UTC (`/2023-11`). A category filter matches
+//! its subcategories too, and its counts break the category down further.
+use crate::article::{url_category, url_source, Article};
+use serde::Serialize;
+use sqlx::types::chrono::NaiveDate;
+use tantivy::collector::{FacetCollector, FacetCounts};
+use tantivy::query::TermQuery;
+use tantivy::schema::{Facet, IndexRecordOption, Schema};
+use tantivy::{Document, Term};
+
+/// Values returned for each facet, the most frequent ones or the latest months.
+pub const MAX_FACET_VALUES: usize = 20;
+
+/// `source` facet of an article, its domain as a single segment. `None` when its
+/// url has no host.
+pub fn source_facet(article: &Article) -> Option<Facet> {
+    let source = url_source(&article.url);
+    (!source.is_empty()).then(|| Facet::from_path([source]))
+}
+
+/// `category` facet of an article, `None` when it sits at the root of its site.
+pub fn category_facet(article: &Article) -> Option<Facet> {
+    parse_category(&url_category(&article.url))
+}
+
+/// `month` facet of an article, e.g. `/2023-11`.
+pub fn month_facet(article: &Article) -> Facet {
+    Facet::from_path([article.timestamp.format("%Y-%m").to_string()])
+}
+
+/// Adds the facets of `article` to its document.
+pub fn add_facets(article: &Article, schema: &Schema, doc: &mut Document) {
+    let field = |name: &str| schema.get_field(name).unwrap();
+    if let Some(source) = source_facet(article) {
+        doc.add_facet(field("source"), source);
+    }
+    if let Some(category) = category_facet(article) {
+        doc.add_facet(field("category"), category);
+    }
+    doc.add_facet(field("month"), month_facet(article));
+}
+
+/// A category given with or without its leading slash, e.g. "the-thao" or
+/// "/the-thao/bong-da". `None` for the root.
+pub fn parse_category(category: &str) -> Option<Facet> {
+    let segments: Vec<String> = category
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    if segments.is_empty() {
+        return None;
+    }
+    Some(Facet::from_path(segments))
+}
+
+/// A month like "2023-11", or "2023-1", as it is indexed: `/2023-11`, `/2023-01`.
+pub fn parse_month(month: &str) -> Option<Facet> {
+    let date = NaiveDate::parse_from_str(&format!("{month}-01"), "%Y-%m-%d").ok()?;
+    Some(Facet::from_path([date.format("%Y-%m").to_string()]))
+}
+
+/// Facet values articles must have, unset ones do not filter.
+#[derive(Debug, Clone, Default)]
+pub struct FacetFilters {
+    /// Domain, e.g. "dantri.com.vn".
+    pub source: Option<String>,
+    /// Category path, e.g. "/the-thao".
+    pub category: Option<String>,
+    /// Month of creation, e.g. "2023-11".
+    pub month: Option<String>,
+}
+
+impl FacetFilters {
+    /// The category filtered on, the root when unset.
+    fn category_or_root(&self) -> Facet {
+        self.category
+            .as_deref()
+            .and_then(parse_category)
+            .unwrap_or_else(Facet::root)
+    }
+
+    /// A term query per set filter.
+    pub fn queries(&self, schema: &Schema) -> tantivy::Result<Vec<TermQuery>> {
+        let mut facets = Vec::new();
+        if let Some(source) = &self.source {
+            facets.push(("source", Facet::from_path([source.to_lowercase()])));
+        }
+        if let Some(category) = self.category.as_deref().and_then(parse_category) {
+            facets.push(("category", category));
+        }
+        if let Some(month) = self.month.as_deref().and_then(parse_month) {
+            facets.push(("month", month));
+        }
+        facets
+            .into_iter()
+            .map(|(name, facet)| {
+                let term = Term::from_facet(schema.get_field(name)?, &facet);
+                Ok(TermQuery::new(term, IndexRecordOption::Basic))
+            })
+            .collect()
+    }
+}
+
+/// Number of hits having a facet value.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FacetCount {
+    pub value: String,
+    pub count: u64,
+}
+
+/// Facet counts of the hits of a search.
+#[derive(Debug, Default, Serialize)]
+pub struct Facets {
+    pub source: Vec<FacetCount>,
+    /// Top level categories, or the subcategories of the category filtered on.
+    pub category: Vec<FacetCount>,
+    /// Latest months first.
+    pub month: Vec<FacetCount>,
+}
+
+pub type FacetCollectors = (FacetCollector, FacetCollector, FacetCollector);
+
+/// Collects the counts of every facet, below the category filtered on if any.
+pub fn facet_collectors(filters: &FacetFilters) -> FacetCollectors {
+    let collector = |name: &str, facet: Facet| {
+        let mut collector = FacetCollector::for_field(name);
+        collector.add_facet(facet);
+        collector
+    };
+    (
+        collector("source", Facet::root()),
+        collector("category", filters.category_or_root()),
+        collector("month", Facet::root()),
+    )
+}
+
+impl Facets {
+    pub fn new(
+        (source, category, month): (FacetCounts, FacetCounts, FacetCounts),
+        filters: &FacetFilters,
+    ) -> Facets {
+        let counts = |facet_counts: &FacetCounts, parent: Facet, path: bool| {
+            facet_counts
+                .get(parent)
+                .map(|(facet, count)| FacetCount {
+                    value: if path {
+                        facet.to_path_string()
+                    } else {
+                        facet.to_path().last().unwrap_or(&"").to_string()
+                    },
+                    count,
+                })
+                .collect::<Vec<_>>()
+        };
+        let by_count = |mut counts: Vec<FacetCount>| {
+            counts.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.value.cmp(&b.value)));
+            counts.truncate(MAX_FACET_VALUES);
+            counts
+        };
+        let mut month = counts(&month, Facet::root(), false);
+        month.reverse();
+        month.truncate(MAX_FACET_VALUES);
+        Facets {
+            source: by_count(counts(&source, Facet::root(), false)),
+            category: by_count(counts(&category, filters.category_or_root(), true)),
+            month,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::facet::{parse_category, parse_month};
+
+    #[test]
+    fn test_parse_filters() {
+        assert_eq!(
+            parse_category("The-Thao/").map(|facet| facet.to_path_string()),
+            Some("/the-thao".to_string())
+        );
+        assert_eq!(parse_category("/"), None);
+        assert_eq!(
+            parse_month("2023-11").map(|facet| facet.to_path_string()),
+            Some("/2023-11".to_string())
+        );
+        assert_eq!(
+            parse_month("2023-1").map(|facet| facet.to_path_string()),
+            Some("/2023-01".to_string())
+        );
+        assert!(parse_month("2023-13").is_none());
+        assert!(parse_month("2023").is_none());
+    }
+}
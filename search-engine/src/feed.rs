@@ -0,0 +1,469 @@
+//! RSS 2.0 and Atom feed ingestion.
+//!
+//! Most Vietnamese news sites publish a feed per section. Every item becomes an
+//! article created at its publication date, with the same id the crawler gives to
+//! its url when it has one, so an article found both ways is indexed once. Feeds
+//! are fetched with the `ETag` and `Last-Modified` validators of the previous poll
+//! so an unchanged feed costs a `304 Not Modified`, and the validators are kept in a
+//! file beside the index, outside of its generations, to survive restarts and
+//! reindexing. Items already indexed are skipped.
+use crate::article::{is_indexed, Article};
+use crate::crawler::{article_id, stored_url};
+use crate::sink::ArticleSink;
+use feed_rs::model::{Entry, Text};
+use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::{Client, StatusCode, Url};
+use scraper::Html;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgPool;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tantivy::Index;
+
+/// Suffix of the file holding the validators of every feed, e.g. `index.feeds.json`.
+const FEED_STATE_SUFFIX: &str = ".feeds.json";
+
+/// File holding the validators of every feed for the index at `index_path`, beside it
+/// rather than in the generation it points to.
+pub fn feed_state_path(index_path: &Path) -> PathBuf {
+    let mut name = index_path.file_name().unwrap_or_default().to_os_string();
+    name.push(FEED_STATE_SUFFIX);
+    index_path.with_file_name(name)
+}
+
+/// Where a feed is read from, http(s) urls are fetched and anything else is a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FeedLocation {
+    Url(Url),
+    File(PathBuf),
+}
+
+impl FromStr for FeedLocation {
+    type Err = std::convert::Infallible;
+
+    fn from_str(location: &str) -> Result<Self, Self::Err> {
+        Ok(match Url::parse(location) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => FeedLocation::Url(url),
+            _ => FeedLocation::File(PathBuf::from(location)),
+        })
+    }
+}
+
+impl fmt::Display for FeedLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FeedLocation::Url(url) => write!(f, "{url}"),
+            FeedLocation::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// Validators of the last successful response of a feed.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// Validators of every feed fetched over http, keyed by url.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FeedState {
+    pub feeds: BTreeMap<String, Validators>,
+}
+
+impl FeedState {
+    pub fn load(path: &Path) -> anyhow::Result<FeedState> {
+        match std::fs::read(path) {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(FeedState::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Written to a temporary file renamed over `path`, so it is never half written.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let mut temporary = path.as_os_str().to_os_string();
+        temporary.push(".tmp");
+        std::fs::write(&temporary, serde_json::to_vec(self)?)?;
+        std::fs::rename(&temporary, path)?;
+        Ok(())
+    }
+}
+
+/// Text of an html fragment with its whitespace collapsed, e.g. a feed description.
+/// Text nodes are joined with a space so paragraphs do not run into each other.
+fn html_text(html: &str) -> String {
+    let fragment = Html::parse_fragment(html);
+    let text = fragment.root_element().text().collect::<Vec<_>>().join(" ");
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn text(text: Option<Text>) -> String {
+    text.map(|text| html_text(&text.content))
+        .unwrap_or_default()
+}
+
+/// Maps a feed item to an article, relative links are resolved against `base`.
+pub fn entry_to_article(entry: Entry, base: Option<&Url>) -> Result<Article, String> {
+    let link = entry
+        .links
+        .iter()
+        .find(|link| matches!(link.rel.as_deref(), None | Some("alternate")))
+        .ok_or("missing link")?;
+    let url = match base {
+        Some(base) => base.join(&link.href),
+        None => Url::parse(&link.href),
+    }
+    .map_err(|e| format!("invalid link {:?}: {e}", link.href))?;
+    let title = text(entry.title);
+    if title.is_empty() {
+        return Err("missing title".to_string());
+    }
+    let timestamp = entry
+        .published
+        .or(entry.updated)
+        .ok_or("missing publication date")?;
+    Ok(Article {
+        id: article_id(&url).map_or(entry.id, str::to_string),
+        title,
+        summary: text(entry.summary),
+        content: entry
+            .content
+            .and_then(|content| content.body)
+            .map(|body| html_text(&body))
+            .unwrap_or_default(),
+        url: stored_url(&url),
+        timestamp,
+    })
+}
+
+/// Reads every item of an RSS or Atom feed, either as an article or as the reason
+/// it was rejected. Relative links are resolved against `location`, or against the
+/// link of the feed itself.
+pub fn parse_feed(
+    body: &[u8],
+    location: Option<&Url>,
+) -> Result<Vec<Result<Article, String>>, feed_rs::parser::ParseFeedError> {
+    let feed = feed_rs::parser::parse(body)?;
+    let base = location.cloned().or_else(|| {
+        feed.links
+            .iter()
+            .find_map(|link| Url::parse(&link.href).ok())
+    });
+    Ok(feed
+        .entries
+        .into_iter()
+        .map(|entry| entry_to_article(entry, base.as_ref()))
+        .collect())
+}
+
+/// Body of a feed and the validators of the response, `None` when it did not change
+/// since `validators` were recorded.
+async fn fetch(
+    client: &Client,
+    url: &Url,
+    validators: &Validators,
+) -> reqwest::Result<Option<(Vec<u8>, Validators)>> {
+    let mut request = client.get(url.clone());
+    if let Some(etag) = &validators.etag {
+        request = request.header(IF_NONE_MATCH, etag);
+    }
+    if let Some(last_modified) = &validators.last_modified {
+        request = request.header(IF_MODIFIED_SINCE, last_modified);
+    }
+    let response = request.send().await?;
+    if response.status() == StatusCode::NOT_MODIFIED {
+        return Ok(None);
+    }
+    let response = response.error_for_status()?;
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+    let validators = Validators {
+        etag: header(ETAG),
+        last_modified: header(LAST_MODIFIED),
+    };
+    Ok(Some((response.bytes().await?.to_vec(), validators)))
+}
+
+/// A feed or feed item that could not be ingested.
+#[derive(Debug)]
+pub struct RejectedFeed {
+    pub feed: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Default)]
+pub struct PollReport {
+    pub indexed: usize,
+    /// Items already indexed.
+    pub skipped: usize,
+    /// Feeds unchanged since the previous poll.
+    pub not_modified: usize,
+    /// Feeds that could not be read, and items that could not be mapped to an article.
+    pub rejected: Vec<RejectedFeed>,
+}
+
+/// Writes the items of every feed that are not in `index` to `sink`, and to Postgres
+/// when a pool is given. Unreadable feeds and items are reported, failing to store an
+/// article stops the poll. The validators are saved once the items are committed.
+pub async fn poll(
+    client: &Client,
+    feeds: &[FeedLocation],
+    state_path: &Path,
+    index: &Index,
+    pool: Option<&PgPool>,
+    mut sink: ArticleSink,
+) -> anyhow::Result<PollReport> {
+    let mut state = FeedState::load(state_path)?;
+    let searcher = index.reader()?.searcher();
+    let mut report = PollReport::default();
+    // The same article is often listed by several section feeds.
+    let mut indexed_ids: HashSet<String> = HashSet::new();
+    for feed in feeds {
+        let body = match feed {
+            FeedLocation::Url(url) => {
+                let validators = state.feeds.get(url.as_str()).cloned().unwrap_or_default();
+                fetch(client, url, &validators)
+                    .await
+                    .map(|response| response.map(|(body, validators)| (body, Some(validators))))
+                    .map_err(|e| e.to_string())
+            }
+            FeedLocation::File(path) => tokio::fs::read(path)
+                .await
+                .map(|body| Some((body, None)))
+                .map_err(|e| e.to_string()),
+        };
+        let (body, validators) = match body {
+            Ok(Some(response)) => response,
+            Ok(None) => {
+                report.not_modified += 1;
+                continue;
+            }
+            Err(reason) => {
+                tracing::warn!("failed to fetch feed {}: {}", feed, reason);
+                report.rejected.push(RejectedFeed {
+                    feed: feed.to_string(),
+                    reason,
+                });
+                continue;
+            }
+        };
+        let location = match feed {
+            FeedLocation::Url(url) => Some(url),
+            FeedLocation::File(_) => None,
+        };
+        let items = match parse_feed(&body, location) {
+            Ok(items) => items,
+            Err(e) => {
+                tracing::warn!("failed to parse feed {}: {}", feed, e);
+                report.rejected.push(RejectedFeed {
+                    feed: feed.to_string(),
+                    reason: e.to_string(),
+                });
+                continue;
+            }
+        };
+        // Kept only once the feed parsed, so a broken response is fetched again.
+        if let (FeedLocation::Url(url), Some(validators)) = (feed, validators) {
+            state.feeds.insert(url.to_string(), validators);
+        }
+        for item in items {
+            let article = match item {
+                Ok(article) => article,
+                Err(reason) => {
+                    report.rejected.push(RejectedFeed {
+                        feed: feed.to_string(),
+                        reason,
+                    });
+                    continue;
+                }
+            };
+            if indexed_ids.contains(&article.id) || is_indexed(&searcher, &article.id)? {
+                report.skipped += 1;
+                continue;
+            }
+            if let Some(pool) = pool {
+                article.save(pool).await?;
+            }
+            sink.upsert(&article).await?;
+            indexed_ids.insert(article.id);
+            report.indexed += 1;
+        }
+    }
+    sink.finish().await?;
+    state.save(state_path)?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crawler::client;
+    use crate::feed::{feed_state_path, parse_feed, poll, FeedLocation, FeedState};
+    use crate::{test_index, test_sink};
+    use axum::http::{header, HeaderMap, StatusCode};
+    use axum::response::IntoResponse;
+    use axum::routing::get;
+    use axum::Router;
+    use reqwest::Url;
+    use std::path::PathBuf;
+
+    const RSS: &str = include_str!("../fixtures/feeds/the-thao.rss");
+    const ATOM: &str = include_str!("../fixtures/feeds/kinh-doanh.atom");
+
+    #[test]
+    fn test_parse_rss() {
+        let items = parse_feed(RSS.as_bytes(), None).unwrap();
+        assert_eq!(items.len(), 3);
+        let article = items[0].as_ref().unwrap();
+        assert_eq!(article.id, "20231101100000000");
+        assert_eq!(article.title, "Đội tuyển Việt Nam thắng đậm");
+        assert_eq!(
+            article.summary,
+            "(Dân trí) - Đội tuyển Việt Nam thắng đậm 4-0 trong trận giao hữu tối qua."
+        );
+        assert_eq!(
+            article.url,
+            "/the-thao/doi-tuyen-viet-nam-thang-dam-20231101100000000.htm"
+        );
+        assert_eq!(article.timestamp.to_rfc3339(), "2023-11-01T10:00:00+00:00");
+        assert_eq!(items[2].as_ref().unwrap_err(), "missing publication date");
+    }
+
+    #[test]
+    fn test_parse_atom() {
+        let items = parse_feed(ATOM.as_bytes(), None).unwrap();
+        let article = items[0].as_ref().unwrap();
+        assert_eq!(article.id, "tag:news.example.vn,2023:gia-vang-tang-manh");
+        assert_eq!(
+            article.url,
+            "https://news.example.vn/kinh-doanh/gia-vang-tang-manh.html"
+        );
+        assert_eq!(
+            article.content,
+            "Sáng nay, giá vàng miếng tăng 500.000 đồng mỗi lượng. Giá vàng thế giới cũng tăng."
+        );
+        assert_eq!(article.timestamp.to_rfc3339(), "2023-11-05T01:00:00+00:00");
+
+        // Relative links, and the update date when there is no publication date.
+        let article = items[1].as_ref().unwrap();
+        assert_eq!(
+            article.url,
+            "https://news.example.vn/kinh-doanh/lai-suat-giam.html"
+        );
+        assert_eq!(article.summary, "Nhiều ngân hàng giảm lãi suất.");
+        assert_eq!(article.timestamp.to_rfc3339(), "2023-11-04T10:00:00+00:00");
+    }
+
+    /// Serves the RSS fixture, and a feed that is not one, with an `ETag`, honoring
+    /// `If-None-Match`.
+    fn stand_in() -> Url {
+        let feed = |etag: &'static str, body: &'static str| {
+            move |headers: HeaderMap| async move {
+                if headers
+                    .get(header::IF_NONE_MATCH)
+                    .map(|etag| etag.as_bytes())
+                    == Some(etag.as_bytes())
+                {
+                    return StatusCode::NOT_MODIFIED.into_response();
+                }
+                ([(header::ETAG, etag)], body).into_response()
+            }
+        };
+        let router = Router::new()
+            .route("/the-thao.rss", get(feed("\"v1\"", RSS)))
+            .route("/broken.rss", get(feed("\"v2\"", "<html></html>")));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(router.into_make_service());
+        tokio::spawn(server);
+        Url::parse(&format!("http://{address}/the-thao.rss")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_poll_indexes_unseen_items() {
+        let index = test_index(&[]);
+        let dir = tempfile::tempdir().unwrap();
+        let state_path = feed_state_path(&dir.path().join("index"));
+        let client = client().unwrap();
+        let url = stand_in();
+        let feeds = [
+            FeedLocation::Url(url.clone()),
+            FeedLocation::File(PathBuf::from("fixtures/feeds/kinh-doanh.atom")),
+        ];
+        let report = poll(
+            &client,
+            &feeds,
+            &state_path,
+            &index,
+            None,
+            test_sink(&index),
+        )
+        .await
+        .unwrap();
+        assert_eq!((report.indexed, report.skipped), (4, 0));
+        assert_eq!(report.rejected.len(), 1);
+        assert_eq!(state_path, dir.path().join("index.feeds.json"));
+        let state = FeedState::load(&state_path).unwrap();
+        assert_eq!(state.feeds[url.as_str()].etag.as_deref(), Some("\"v1\""));
+
+        // The feed did not change, the file is read again but its items are known.
+        let report = poll(
+            &client,
+            &feeds,
+            &state_path,
+            &index,
+            None,
+            test_sink(&index),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.not_modified, 1);
+        assert_eq!((report.indexed, report.skipped), (0, 2));
+        assert_eq!(index.reader().unwrap().searcher().num_docs(), 4);
+
+        let missing = [FeedLocation::File(PathBuf::from("missing.rss"))];
+        let report = poll(
+            &client,
+            &missing,
+            &state_path,
+            &index,
+            None,
+            test_sink(&index),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.rejected.len(), 1);
+
+        // The validators of a feed that failed to parse are not kept, so it is
+        // fetched again rather than seen as unchanged.
+        let broken = [FeedLocation::Url(url.join("broken.rss").unwrap())];
+        for _ in 0..2 {
+            let report = poll(
+                &client,
+                &broken,
+                &state_path,
+                &index,
+                None,
+                test_sink(&index),
+            )
+            .await
+            .unwrap();
+            assert_eq!((report.rejected.len(), report.not_modified), (1, 0));
+        }
+        let state = FeedState::load(&state_path).unwrap();
+        assert_eq!(state.feeds.len(), 1);
+        assert_eq!(
+            "https://a.vn/x.rss".parse::<FeedLocation>().unwrap(),
+            FeedLocation::Url(Url::parse("https://a.vn/x.rss").unwrap())
+        );
+    }
+}
@@ -0,0 +1,176 @@
+//! Typo tolerant matching.
+//!
+//! Every term of a parsed query is folded to ASCII, so misplaced or missing tone
+//! marks cost nothing, and matched within an edit distance growing with its
+//! length. A fuzzy match only adds a small constant score, so articles holding
+//! the exact terms always rank above articles that merely hold similar ones.
+use crate::config::{Boosts, FuzzyConfig};
+use crate::vietnamese::fold;
+use crate::wrapper::SearchField;
+use std::collections::BTreeSet;
+use tantivy::query::{BooleanQuery, BoostQuery, FuzzyTermQuery, Occur, Query};
+use tantivy::query_grammar::UserInputAst;
+use tantivy::schema::{Field, Schema, Type};
+use tantivy::{Score, Term};
+
+/// Weight of a fuzzy match relative to the boost of its field.
+pub const FUZZY_WEIGHT: Score = 0.1;
+
+/// Number of edits tolerated in a term of `length` characters.
+pub fn edit_distance(length: usize, config: &FuzzyConfig) -> u8 {
+    if length >= config.two_edits_from {
+        2
+    } else if length >= config.one_edit_from {
+        1
+    } else {
+        0
+    }
+}
+
+/// The clauses of a query excluded with `-` or `NOT`.
+pub fn excluded_clauses(ast: &UserInputAst) -> Vec<UserInputAst> {
+    match ast {
+        UserInputAst::Clause(clauses) => clauses
+            .iter()
+            .flat_map(|(occur, clause)| match occur {
+                Some(Occur::MustNot) => vec![clause.clone()],
+                _ => excluded_clauses(clause),
+            })
+            .collect(),
+        UserInputAst::Boost(ast, _) => excluded_clauses(ast),
+        UserInputAst::Leaf(_) => Vec::new(),
+    }
+}
+
+/// The text terms of `query`, folded.
+fn folded_terms(query: &dyn Query) -> BTreeSet<(Field, String)> {
+    let mut terms = BTreeSet::new();
+    query.query_terms(&mut |term, _| {
+        if term.typ() != Type::Str {
+            return;
+        }
+        if let Some(text) = term.value().as_str() {
+            terms.insert((term.field(), fold(text)));
+        }
+    });
+    terms
+}
+
+/// Matches the text terms of `query` within their edit distance, but the terms of
+/// `excluded`. `None` when no term is left.
+pub fn fuzzy_query(
+    query: &dyn Query,
+    excluded: &[Box<dyn Query>],
+    schema: &Schema,
+    boosts: &Boosts,
+    config: &FuzzyConfig,
+) -> Option<Box<dyn Query>> {
+    let mut terms = folded_terms(query);
+    for query in excluded {
+        for term in folded_terms(query.as_ref()) {
+            terms.remove(&term);
+        }
+    }
+    let clauses: Vec<(Occur, Box<dyn Query>)> = terms
+        .into_iter()
+        .map(|(field, text)| {
+            let distance = edit_distance(text.chars().count(), config);
+            let boost = SearchField::ALL
+                .into_iter()
+                .find(|search_field| schema.get_field_name(field) == search_field.name())
+                .map_or(1.0, |search_field| search_field.boost(boosts));
+            let fuzzy = FuzzyTermQuery::new(Term::from_field_text(field, &text), distance, true);
+            let query: Box<dyn Query> =
+                Box::new(BoostQuery::new(Box::new(fuzzy), boost * FUZZY_WEIGHT));
+            (Occur::Should, query)
+        })
+        .collect();
+    if clauses.is_empty() {
+        return None;
+    }
+    Some(Box::new(BooleanQuery::new(clauses)))
+}
+
+/// Matches both the exact and the similar terms of `query`, articles matching one of
+/// the `excluded` clauses of `query` are left out whatever their terms.
+pub fn with_fuzzy(
+    query: Box<dyn Query>,
+    excluded: &[Box<dyn Query>],
+    schema: &Schema,
+    boosts: &Boosts,
+    config: &FuzzyConfig,
+) -> Box<dyn Query> {
+    let Some(fuzzy) = fuzzy_query(query.as_ref(), excluded, schema, boosts, config) else {
+        return query;
+    };
+    let query: Box<dyn Query> = Box::new(BooleanQuery::new(vec![
+        (Occur::Should, query),
+        (Occur::Should, fuzzy),
+    ]));
+    if excluded.is_empty() {
+        return query;
+    }
+    let mut clauses = vec![(Occur::Must, query)];
+    clauses.extend(
+        excluded
+            .iter()
+            .map(|excluded| (Occur::MustNot, excluded.box_clone())),
+    );
+    Box::new(BooleanQuery::new(clauses))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::FuzzyConfig;
+    use crate::fuzzy::edit_distance;
+    use crate::wrapper::{query_wrapper, SearchOptions};
+    use crate::{test_article, test_state};
+
+    #[test]
+    fn test_edit_distance_grows_with_length() {
+        let config = FuzzyConfig::default();
+        assert_eq!(edit_distance(3, &config), 0);
+        assert_eq!(edit_distance(5, &config), 1);
+        assert_eq!(edit_distance(9, &config), 2);
+    }
+
+    #[test]
+    fn test_fuzzy_matches_rank_below_exact_ones() {
+        let state = test_state(&[
+            test_article("1", "Thành phố Hồ Chí Minh", 1),
+            test_article("2", "Thanh phó", 1),
+        ]);
+        let search = |query: &str, fuzzy: bool| {
+            let options = SearchOptions {
+                fuzzy,
+                ..SearchOptions::default()
+            };
+            query_wrapper(&state, query, &options).unwrap()
+        };
+
+        // Exact matches are found without fuzzy matching.
+        let results = search("thành", false);
+        assert!(!results.fuzzy);
+        assert_eq!(results.hits.len(), 1);
+
+        // Typos and misplaced tone marks.
+        let results = search("thnàh phô", true);
+        assert!(results.fuzzy);
+        assert_eq!(results.count, 2);
+
+        let results = search("phó", true);
+        let ids: Vec<_> = results.hits.iter().map(|hit| hit.id.as_str()).collect();
+        assert_eq!(ids, ["2", "1"]);
+
+        // Zero hits fall back to fuzzy matching.
+        let results = search("Minhh", false);
+        assert!(results.fuzzy);
+        assert_eq!(results.count, 1);
+
+        // Excluded terms stay excluded.
+        let results = search("thanh -phố", true);
+        let ids: Vec<_> = results.hits.iter().map(|hit| hit.id.as_str()).collect();
+        assert_eq!(ids, ["2"]);
+        assert_eq!(search("Minhh NOT hồ", false).count, 0);
+    }
+}
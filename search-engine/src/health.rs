@@ -0,0 +1,82 @@
+//! Liveness of the server and of the backends it depends on.
+use crate::db;
+use crate::writer::CommitStatus;
+use crate::AppState;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DatabaseStatus {
+    /// The server runs index-only.
+    Disabled,
+    Ok,
+    Unavailable,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Health {
+    /// Number of searchable articles.
+    pub num_docs: u64,
+    pub database: DatabaseStatus,
+    /// Progress of the index writer, absent when the server does not accept writes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub writer: Option<CommitStatus>,
+}
+
+/// Answers `503 Service Unavailable` when the configured database can not be reached.
+pub async fn health(State(app_state): State<AppState>) -> (StatusCode, Json<Health>) {
+    let num_docs = app_state.reader().searcher().num_docs();
+    let database = match &app_state.pool {
+        None => DatabaseStatus::Disabled,
+        Some(pool) => match db::ping(pool).await {
+            Ok(()) => DatabaseStatus::Ok,
+            Err(e) => {
+                tracing::error!("database health check failed: {:?}", e);
+                DatabaseStatus::Unavailable
+            }
+        },
+    };
+    let status = match database {
+        DatabaseStatus::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::OK,
+    };
+    let writer = app_state.writer().map(|writer| writer.status());
+    (
+        status,
+        Json(Health {
+            num_docs,
+            database,
+            writer,
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::health::{health, DatabaseStatus};
+    use crate::test_state;
+    use axum::extract::State;
+    use axum::http::StatusCode;
+    use sqlx::postgres::PgPoolOptions;
+
+    #[tokio::test]
+    async fn test_health() {
+        let state = test_state(&[]);
+        let (status, health_) = health(State(state.clone())).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(matches!(health_.database, DatabaseStatus::Disabled));
+        assert!(health_.writer.is_none());
+
+        // Nothing listens on port 1.
+        let pool = PgPoolOptions::new()
+            .acquire_timeout(std::time::Duration::from_millis(500))
+            .connect_lazy("postgres://postgres@127.0.0.1:1/articles")
+            .unwrap();
+        let (status, health_) = health(State(state.with_pool(pool))).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(matches!(health_.database, DatabaseStatus::Unavailable));
+    }
+}
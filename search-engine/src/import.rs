@@ -0,0 +1,174 @@
+//! Bulk import of articles from CSV files laid out like `mock_data.csv`:
+//!
+//! ```text
+//! id,title,summary,content,url,timestamp
+//! 1,Morbi non lectus.,...,...,http://...,2022-12-04 13:58:51
+//! ```
+use crate::article::Article;
+use crate::sink::ArticleSink;
+use serde::Deserialize;
+use sqlx::postgres::PgPool;
+use sqlx::types::chrono::{DateTime, NaiveDateTime, Utc};
+use std::io::Read;
+
+/// Rows written to the index between two commits.
+const COMMIT_EVERY: usize = 10_000;
+/// Rows imported between two progress reports.
+const REPORT_EVERY: usize = 1_000;
+
+#[derive(Debug, Deserialize)]
+struct CsvRow {
+    id: String,
+    title: String,
+    summary: String,
+    content: String,
+    url: String,
+    timestamp: String,
+}
+
+/// A row that could not be imported, `line` is the line number in the CSV file.
+#[derive(Debug)]
+pub struct RejectedRow {
+    pub line: u64,
+    pub reason: String,
+}
+
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub imported: usize,
+    pub rejected: Vec<RejectedRow>,
+}
+
+/// Accepts both `2022-12-04 13:58:51` (assumed UTC) and RFC 3339 timestamps.
+pub fn parse_timestamp(value: &str) -> Option<DateTime<Utc>> {
+    let value = value.trim();
+    DateTime::parse_from_rfc3339(value)
+        .map(|date| date.with_timezone(&Utc))
+        .or_else(|_| {
+            NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S").map(|date| date.and_utc())
+        })
+        .ok()
+}
+
+impl TryFrom<CsvRow> for Article {
+    type Error = String;
+
+    fn try_from(row: CsvRow) -> Result<Self, Self::Error> {
+        if row.id.trim().is_empty() {
+            return Err("missing id".to_string());
+        }
+        if row.title.trim().is_empty() {
+            return Err("missing title".to_string());
+        }
+        if row.url.trim().is_empty() {
+            return Err("missing url".to_string());
+        }
+        let created_time = parse_timestamp(&row.timestamp)
+            .ok_or_else(|| format!("invalid timestamp {:?}", row.timestamp))?;
+        Ok(Article {
+            id: row.id.trim().to_string(),
+            title: row.title,
+            summary: row.summary,
+            content: row.content,
+            url: row.url,
+            timestamp: created_time,
+        })
+    }
+}
+
+/// Yields every row of a CSV file, either as a valid article or as the reason it was rejected.
+pub fn read_articles<R: Read>(reader: R) -> impl Iterator<Item = Result<Article, RejectedRow>> {
+    let mut reader = csv::Reader::from_reader(reader);
+    let headers = reader.headers().cloned();
+    reader.into_records().map(move |record| {
+        let record = record.map_err(|e| RejectedRow {
+            line: e.position().map(|p| p.line()).unwrap_or_default(),
+            reason: e.to_string(),
+        })?;
+        let line = record.position().map(|p| p.line()).unwrap_or_default();
+        let headers = headers.as_ref().map_err(|e| RejectedRow {
+            line: 1,
+            reason: e.to_string(),
+        })?;
+        record
+            .deserialize::<CsvRow>(Some(headers))
+            .map_err(|e| e.to_string())
+            .and_then(Article::try_from)
+            .map_err(|reason| RejectedRow { line, reason })
+    })
+}
+
+/// Writes the articles of a CSV file to `sink`, and to Postgres when a pool is given.
+/// Articles already present with the same id are replaced.
+pub async fn import_csv<R: Read>(
+    reader: R,
+    pool: Option<&PgPool>,
+    mut sink: ArticleSink,
+) -> anyhow::Result<ImportReport> {
+    let mut report = ImportReport::default();
+    for article in read_articles(reader) {
+        let article = match article {
+            Ok(article) => article,
+            Err(rejected) => {
+                tracing::warn!("rejected line {}: {}", rejected.line, rejected.reason);
+                report.rejected.push(rejected);
+                continue;
+            }
+        };
+        if let Some(pool) = pool {
+            article.save(pool).await?;
+        }
+        sink.upsert(&article).await?;
+        report.imported += 1;
+        if report.imported % COMMIT_EVERY == 0 {
+            sink.commit().await?;
+        }
+        if report.imported % REPORT_EVERY == 0 {
+            tracing::info!("imported {} articles", report.imported);
+        }
+    }
+    sink.finish().await?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::import::{import_csv, read_articles};
+    use crate::{test_index, test_sink};
+
+    const CSV: &str = "\
+id,title,summary,content,url,timestamp
+1,Morbi non lectus.,Curabitur gravida,\"Etiam, faucibus\",http://a.vn/1,2022-12-04 13:58:51
+2,,summary,content,http://a.vn/2,2022-12-04 13:58:51
+3,Title,summary,content,http://a.vn/3,yesterday
+";
+
+    #[test]
+    fn test_read_articles_rejects_invalid_rows() {
+        let rows: Vec<_> = read_articles(CSV.as_bytes()).collect();
+        assert_eq!(rows.len(), 3);
+        let article = rows[0].as_ref().unwrap();
+        assert_eq!(article.content, "Etiam, faucibus");
+        assert_eq!(article.timestamp.to_rfc3339(), "2022-12-04T13:58:51+00:00");
+        let rejected = rows[1].as_ref().unwrap_err();
+        assert_eq!(
+            (rejected.line, rejected.reason.as_str()),
+            (3, "missing title")
+        );
+        let rejected = rows[2].as_ref().unwrap_err();
+        assert_eq!(rejected.line, 4);
+    }
+
+    #[tokio::test]
+    async fn test_import_csv_is_idempotent() {
+        let index = test_index(&[]);
+        for _ in 0..2 {
+            let report = import_csv(CSV.as_bytes(), None, test_sink(&index))
+                .await
+                .unwrap();
+            assert_eq!(report.imported, 1);
+            assert_eq!(report.rejected.len(), 2);
+        }
+        assert_eq!(index.reader().unwrap().searcher().num_docs(), 1);
+    }
+}
@@ -0,0 +1,160 @@
+//! Incremental indexing of the Postgres `article` table.
+//!
+//! Rows are read in `(updated_at, id)` order and the last indexed row is kept as
+//! a `Checkpoint` next to the index, so a restart only picks up rows that were
+//! added or changed since the previous sync. Every row replaces the document
+//! with the same `id`, which keeps a sync idempotent even when it is replayed.
+//!
+//! `updated_at` is set by a trigger to the start of the writing transaction, so a
+//! row committed after a sync may carry an older `updated_at` than its checkpoint.
+//! A sync re-reads the rows of the `SYNC_OVERLAP` before the checkpoint for them,
+//! rows from transactions open for longer are only picked up by a `reindex`.
+//! Deleted rows are not seen by a sync either: rows deleted through the write API
+//! leave the index with them, others stay in it until the next `reindex`.
+use crate::article::Article;
+use crate::sink::ArticleSink;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgPool;
+use sqlx::types::chrono::{DateTime, Utc};
+use sqlx::{FromRow, Row};
+use std::path::Path;
+use std::time::Duration;
+use tantivy::directory::Directory;
+use tantivy::{Index, IndexWriter, Opstamp, Term};
+
+/// Heap given to the `IndexWriter` when none is configured.
+pub const DEFAULT_WRITER_MEMORY: usize = 50_000_000;
+/// Name of the checkpoint file, dotfiles are left alone by tantivy's garbage collection.
+pub const CHECKPOINT_FILE: &str = ".sync_checkpoint.json";
+/// Rows fetched and committed at once.
+const BATCH_SIZE: i64 = 1000;
+/// How far before its checkpoint a sync starts reading, for the rows of
+/// transactions that committed after the previous sync.
+const SYNC_OVERLAP: Duration = Duration::from_secs(10 * 60);
+
+/// High-water mark of the rows already in the index.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub updated_at: DateTime<Utc>,
+    pub id: String,
+}
+
+impl Checkpoint {
+    pub fn load(index: &Index) -> anyhow::Result<Option<Checkpoint>> {
+        let path = Path::new(CHECKPOINT_FILE);
+        if !index.directory().exists(path)? {
+            return Ok(None);
+        }
+        let data = index.directory().atomic_read(path)?;
+        Ok(Some(serde_json::from_slice(&data)?))
+    }
+
+    pub fn save(&self, index: &Index) -> anyhow::Result<()> {
+        let data = serde_json::to_vec(self)?;
+        index
+            .directory()
+            .atomic_write(Path::new(CHECKPOINT_FILE), &data)?;
+        Ok(())
+    }
+}
+
+/// Replaces the document holding `article.id`, if any, with `article`.
+pub fn upsert_article(writer: &IndexWriter, article: &Article) -> tantivy::Result<Opstamp> {
+    let schema = writer.index().schema();
+    let id_field = schema.get_field("id")?;
+    writer.delete_term(Term::from_field_text(id_field, &article.id));
+    writer.add_document(article.to_document(&schema))
+}
+
+/// Writes every row added or updated since the last checkpoint of `index`, less the
+/// `SYNC_OVERLAP`, to `sink`, a writer of `index`, and returns how many rows were
+/// indexed. The checkpoint is saved after each committed batch.
+pub async fn sync_from_postgres(
+    pool: &PgPool,
+    index: &Index,
+    mut sink: ArticleSink,
+) -> anyhow::Result<usize> {
+    let overlap = chrono::Duration::from_std(SYNC_OVERLAP)?;
+    let mut checkpoint = Checkpoint::load(index)?.map(|checkpoint| Checkpoint {
+        updated_at: checkpoint.updated_at - overlap,
+        id: String::new(),
+    });
+    let mut indexed = 0;
+    loop {
+        let rows = match &checkpoint {
+            Some(checkpoint) => {
+                sqlx::query(
+                    r#"select * from "article" where (updated_at, id) > ($1, $2)
+                       order by updated_at, id limit $3"#,
+                )
+                .bind(checkpoint.updated_at)
+                .bind(&checkpoint.id)
+                .bind(BATCH_SIZE)
+                .fetch_all(pool)
+                .await?
+            }
+            None => {
+                sqlx::query(r#"select * from "article" order by updated_at, id limit $1"#)
+                    .bind(BATCH_SIZE)
+                    .fetch_all(pool)
+                    .await?
+            }
+        };
+        let Some(last) = rows.last() else {
+            break;
+        };
+        let next = Checkpoint {
+            updated_at: last.try_get("updated_at")?,
+            id: last.try_get("id")?,
+        };
+        for row in &rows {
+            sink.upsert(&Article::from_row(row)?).await?;
+        }
+        sink.commit().await?;
+        next.save(index)?;
+        indexed += rows.len();
+        tracing::info!("indexed {} articles up to {:?}", indexed, next);
+        checkpoint = Some(next);
+    }
+    sink.finish().await?;
+    Ok(indexed)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::indexer::{upsert_article, Checkpoint};
+    use crate::{test_article, test_index};
+    use sqlx::types::chrono::{TimeZone, Utc};
+    use tantivy::query::TermQuery;
+    use tantivy::schema::IndexRecordOption;
+    use tantivy::{collector::Count, Term};
+
+    #[test]
+    fn test_checkpoint_round_trip() {
+        let index = test_index(&[]);
+        assert_eq!(Checkpoint::load(&index).unwrap(), None);
+        let checkpoint = Checkpoint {
+            updated_at: Utc.with_ymd_and_hms(2023, 11, 1, 10, 0, 0).unwrap(),
+            id: "42".to_string(),
+        };
+        checkpoint.save(&index).unwrap();
+        assert_eq!(Checkpoint::load(&index).unwrap(), Some(checkpoint));
+    }
+
+    #[test]
+    fn test_upsert_replaces_document() {
+        let index = test_index(&[test_article("1", "cũ", 1)]);
+        let mut writer = index.writer(15_000_000).unwrap();
+        upsert_article(&writer, &test_article("1", "mới", 1)).unwrap();
+        writer.commit().unwrap();
+
+        let searcher = index.reader().unwrap().searcher();
+        let id_field = index.schema().get_field("id").unwrap();
+        let query = TermQuery::new(
+            Term::from_field_text(id_field, "1"),
+            IndexRecordOption::Basic,
+        );
+        assert_eq!(searcher.search(&query, &Count).unwrap(), 1);
+        assert_eq!(searcher.num_docs(), 1);
+    }
+}
@@ -1,18 +1,243 @@
+use config::{Boosts, CommitConfig, FuzzyConfig};
+use spelling::VocabularyCache;
 use sqlx::postgres::PgPool;
+use std::sync::{Arc, RwLock};
+use suggest::QueryLog;
+use tantivy::query::QueryParser;
 use tantivy::schema::IndexRecordOption;
 use tantivy::{
-    schema::{Schema, TextFieldIndexing, TextOptions, STRING, TEXT},
+    schema::{
+        DateOptions, DateTimePrecision, Schema, TextFieldIndexing, TextOptions, STORED, STRING,
+        TEXT,
+    },
     tokenizer::Token,
-    Index,
+    Index, IndexReader, ReloadPolicy,
 };
+use wrapper::SearchField;
 pub mod alpha_only_filter;
 pub mod article;
+pub mod config;
+pub mod crawler;
+pub mod db;
+pub mod error;
+pub mod facet;
+pub mod feed;
+pub mod fuzzy;
+pub mod health;
+pub mod import;
+pub mod indexer;
+pub mod pagination;
+pub mod reindex;
+pub mod sink;
+pub mod snippet;
+pub mod spelling;
+pub mod suggest;
+pub mod vietnamese;
 pub mod wrapper;
-#[derive(Debug, Clone)]
-pub struct AppState {
-    // pub pool: PgPool,
+pub mod write;
+pub mod writer;
+/// The index being served and what is bound to it, replaced as a whole when a
+/// reindex swaps in a new generation.
+#[derive(Clone)]
+pub struct ServedIndex {
     pub index: Index,
+    /// Shared by every handler, so searches reuse the same searcher pool and file watcher.
+    pub reader: IndexReader,
+    /// Searches the title, summary and content fields by default.
+    pub query_parser: QueryParser,
+    /// The only writer of the index, set when the server accepts writes.
+    pub writer: Option<writer::WriterHandle>,
+}
+
+impl ServedIndex {
+    fn open(index: Index, boosts: &Boosts) -> tantivy::Result<ServedIndex> {
+        let reader = index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::OnCommit)
+            .try_into()?;
+        let query_parser = build_query_parser(&index, boosts, &SearchField::ALL)?;
+        Ok(ServedIndex {
+            index,
+            reader,
+            query_parser,
+            writer: None,
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct AppState {
+    /// Only set when the server runs with a database.
+    pub pool: Option<PgPool>,
+    served: Arc<RwLock<Arc<ServedIndex>>>,
+    pub boosts: Boosts,
+    pub fuzzy: FuzzyConfig,
+    /// Queries that had hits, completed by `/api/suggest`.
+    pub query_log: Arc<QueryLog>,
+    /// Terms suggested for misspelled queries, read once per searcher generation.
+    pub vocabulary: Arc<VocabularyCache>,
+    /// Bearer token of the write API.
+    pub api_token: Option<String>,
+    /// Heap and commit settings of the writer, spawned again for a reopened index.
+    writer_config: Option<(usize, CommitConfig)>,
+}
+
+impl AppState {
+    pub fn new(index: Index) -> tantivy::Result<AppState> {
+        let boosts = Boosts::default();
+        let served = ServedIndex::open(index, &boosts)?;
+        Ok(AppState {
+            pool: None,
+            served: Arc::new(RwLock::new(Arc::new(served))),
+            boosts,
+            fuzzy: FuzzyConfig::default(),
+            query_log: Arc::default(),
+            vocabulary: Arc::default(),
+            api_token: None,
+            writer_config: None,
+        })
+    }
+
+    /// Replaces the served index, only while the state is being built.
+    fn with_served(self, served: ServedIndex) -> AppState {
+        *self.served.write().unwrap() = Arc::new(served);
+        self
+    }
+
+    pub fn with_boosts(self, boosts: Boosts) -> tantivy::Result<AppState> {
+        let mut served = (*self.served()).clone();
+        served.query_parser = build_query_parser(&served.index, &boosts, &SearchField::ALL)?;
+        Ok(AppState { boosts, ..self }.with_served(served))
+    }
+
+    pub fn with_fuzzy(self, fuzzy: FuzzyConfig) -> AppState {
+        AppState { fuzzy, ..self }
+    }
+
+    /// The index served right now, the one to use throughout a request.
+    pub fn served(&self) -> Arc<ServedIndex> {
+        self.served.read().unwrap().clone()
+    }
+
+    pub fn index(&self) -> Index {
+        self.served().index.clone()
+    }
+
+    pub fn reader(&self) -> IndexReader {
+        self.served().reader.clone()
+    }
+
+    pub fn writer(&self) -> Option<writer::WriterHandle> {
+        self.served().writer.clone()
+    }
+
+    /// The shared parser when every field is searched, a new one restricted to `fields` otherwise.
+    pub fn query_parser(&self, fields: &[SearchField]) -> tantivy::Result<QueryParser> {
+        let served = self.served();
+        if fields.is_empty() {
+            return Ok(served.query_parser.clone());
+        }
+        build_query_parser(&served.index, &self.boosts, fields)
+    }
+
+    /// Accepts writes authenticated with `api_token`, through a background writer with
+    /// `writer_memory` bytes of heap committing as `commit` says.
+    pub fn with_writer(
+        self,
+        api_token: String,
+        writer_memory: usize,
+        commit: CommitConfig,
+    ) -> anyhow::Result<AppState> {
+        let mut served = (*self.served()).clone();
+        served.writer = Some(spawn_writer(&served, writer_memory, commit)?);
+        Ok(AppState {
+            api_token: Some(api_token),
+            writer_config: Some((writer_memory, commit)),
+            ..self
+        }
+        .with_served(served))
+    }
+
+    pub fn with_pool(self, pool: PgPool) -> AppState {
+        AppState {
+            pool: Some(pool),
+            ..self
+        }
+    }
+
+    /// Makes the last commit visible to searches right away, instead of waiting
+    /// for the reader to pick it up asynchronously.
+    pub fn reload(&self) -> tantivy::Result<()> {
+        self.served().reader.reload()
+    }
+
+    /// Serves `index` from now on, e.g. the generation a reindex swapped in, then
+    /// indexes the database rows written since its checkpoint, such as those written
+    /// while it was built. Returns how many rows were indexed.
+    ///
+    /// The writer moves to `index`: the one of the previous index first commits what
+    /// it accepted and releases that index, so the two never run at once. Writes
+    /// failing with `WriterError::Stopped` in between are not retried. Without a
+    /// writer, the rows are indexed with one of `writer_memory` bytes.
+    pub async fn reopen(&self, index: Index, writer_memory: usize) -> anyhow::Result<usize> {
+        let mut served = ServedIndex::open(index.clone(), &self.boosts)?;
+        if let Some(writer) = self.writer() {
+            // Its failures were reported to the requests waiting on it.
+            if let Err(e) = writer.shutdown().await {
+                tracing::error!("the writer of the previous index failed: {:?}", e);
+            }
+        }
+        if let Some((writer_memory, commit)) = self.writer_config {
+            served.writer = Some(spawn_writer(&served, writer_memory, commit)?);
+        }
+        *self.served.write().unwrap() = Arc::new(served);
+        let Some(pool) = &self.pool else {
+            return Ok(0);
+        };
+        let sink = match self.writer() {
+            Some(writer) => sink::ArticleSink::Writer(writer, Vec::new()),
+            None => sink::ArticleSink::open(&index, writer_memory)?,
+        };
+        let indexed = indexer::sync_from_postgres(pool, &index, sink).await?;
+        self.reload()?;
+        Ok(indexed)
+    }
 }
+
+fn spawn_writer(
+    served: &ServedIndex,
+    writer_memory: usize,
+    commit: CommitConfig,
+) -> anyhow::Result<writer::WriterHandle> {
+    let writer = served.index.writer(writer_memory)?;
+    Ok(writer::WriterHandle::spawn(
+        writer,
+        served.reader.clone(),
+        commit,
+    )?)
+}
+
+fn build_query_parser(
+    index: &Index,
+    boosts: &Boosts,
+    fields: &[SearchField],
+) -> tantivy::Result<QueryParser> {
+    let schema = index.schema();
+    let default_fields = fields
+        .iter()
+        .map(|field| schema.get_field(field.name()))
+        .collect::<tantivy::Result<Vec<_>>>()?;
+    let mut query_parser = QueryParser::for_index(index, default_fields.clone());
+    for (field, default_field) in fields.iter().zip(default_fields) {
+        query_parser.set_field_boost(default_field, field.boost(boosts));
+    }
+    Ok(query_parser)
+}
+
+/// Version of `get_article_schema`, recorded in every index. Bump it with any change
+/// to the schema, existing indexes then have to be rebuilt with `reindex`.
+pub const SCHEMA_VERSION: u32 = 1;
+
 pub fn get_article_schema() -> Schema {
     let text_field_indexing = TextFieldIndexing::default()
         .set_tokenizer("custom")
@@ -21,16 +246,73 @@ pub fn get_article_schema() -> Schema {
     let text_options = TextOptions::default().set_indexing_options(text_field_indexing);
     let text_option_stored = text_options.clone().set_stored();
     let mut schema_builder = Schema::builder();
-    schema_builder.add_text_field("id", STRING | TEXT);
+    schema_builder.add_text_field("id", STRING | TEXT | STORED);
     schema_builder.add_text_field("title", text_option_stored.clone());
-    schema_builder.add_text_field("content", text_options.clone());
+    schema_builder.add_text_field("content", text_option_stored.clone());
     schema_builder.add_text_field("summary", text_option_stored.clone());
     schema_builder.add_text_field("url", text_option_stored.clone());
-    schema_builder.add_text_field("created_time", text_options.clone());
+    // Facets derived from the url and creation date, see `facet`.
+    schema_builder.add_facet_field("source", STORED);
+    schema_builder.add_facet_field("category", STORED);
+    schema_builder.add_facet_field("month", STORED);
+    // Indexed for range filters and fast for sorting by date.
+    let date_options = DateOptions::default()
+        .set_indexed()
+        .set_stored()
+        .set_fast()
+        .set_precision(DateTimePrecision::Seconds);
+    schema_builder.add_date_field("created_time", date_options);
 
     schema_builder.build()
 }
 
+/// Registers the "custom" tokenizer used by the text fields of the article schema.
+pub fn register_tokenizer(index: &Index, analyzer: config::Analyzer) {
+    index.tokenizers().register("custom", analyzer.build());
+}
+
+/// Builds the state of a server searching an in-memory index holding the given articles.
+#[cfg(test)]
+pub(crate) fn test_state(articles: &[article::Article]) -> AppState {
+    AppState::new(test_index(articles)).unwrap()
+}
+
+/// An article of `/the-thao` created on November `day` 2023, without summary or content.
+#[cfg(test)]
+pub(crate) fn test_article(id: &str, title: &str, day: u32) -> article::Article {
+    use chrono::TimeZone;
+    article::Article {
+        id: id.to_string(),
+        title: title.to_string(),
+        summary: String::new(),
+        content: String::new(),
+        url: format!("/the-thao/{id}.htm"),
+        timestamp: chrono::Utc
+            .with_ymd_and_hms(2023, 11, day, 10, 0, 0)
+            .unwrap(),
+    }
+}
+
+/// Builds an in-memory index holding the given articles.
+#[cfg(test)]
+pub(crate) fn test_index(articles: &[article::Article]) -> Index {
+    let schema = get_article_schema();
+    let index = Index::create_in_ram(schema.clone());
+    register_tokenizer(&index, config::Analyzer::default());
+    let mut writer = index.writer(15_000_000).unwrap();
+    for article in articles {
+        writer.add_document(article.to_document(&schema)).unwrap();
+    }
+    writer.commit().unwrap();
+    index
+}
+
+/// Writes to `index` with a writer of its own, as the commands do without `--server`.
+#[cfg(test)]
+pub(crate) fn test_sink(index: &Index) -> sink::ArticleSink {
+    sink::ArticleSink::open(index, 15_000_000).unwrap()
+}
+
 pub fn assert_token(token: &Token, position: usize, text: &str, from: usize, to: usize) {
     assert_eq!(
         token.position, position,
@@ -0,0 +1,229 @@
+//! Offset/limit paging for search results.
+//!
+//! Clients can either page explicitly with `offset`/`limit` or follow the
+//! opaque `next_cursor` returned with every page. The cursor is just the next
+//! window encoded as url-safe base64, so it stays valid for as long as the
+//! query does not change.
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde::Serialize;
+
+/// Page size used when the request does not ask for one.
+pub const DEFAULT_LIMIT: usize = 20;
+/// Largest page a single request may ask for.
+pub const MAX_LIMIT: usize = 100;
+/// Deepest offset a request may ask for, collecting the top hits costs memory in
+/// `offset + limit`.
+pub const MAX_OFFSET: usize = 10_000;
+
+const CURSOR_PREFIX: &str = "v1";
+
+/// A window over the ranked hits of a query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Page {
+    pub offset: usize,
+    pub limit: usize,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum InvalidPage {
+    Cursor,
+    Offset,
+}
+
+impl InvalidPage {
+    /// The request parameter at fault.
+    pub fn field(&self) -> &'static str {
+        match self {
+            InvalidPage::Cursor => "cursor",
+            InvalidPage::Offset => "offset",
+        }
+    }
+}
+
+impl std::fmt::Display for InvalidPage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InvalidPage::Cursor => f.write_str("invalid pagination cursor"),
+            InvalidPage::Offset => write!(f, "offset must be at most {MAX_OFFSET}"),
+        }
+    }
+}
+
+impl std::error::Error for InvalidPage {}
+
+impl Default for Page {
+    fn default() -> Self {
+        Page {
+            offset: 0,
+            limit: DEFAULT_LIMIT,
+        }
+    }
+}
+
+impl Page {
+    /// Builds the requested page. A cursor takes precedence over an explicit
+    /// offset, while an explicit limit always wins over the cursor's one. Offsets
+    /// past `MAX_OFFSET` are refused.
+    pub fn resolve(
+        offset: Option<usize>,
+        limit: Option<usize>,
+        cursor: Option<&str>,
+    ) -> Result<Page, InvalidPage> {
+        let page = match cursor {
+            Some(cursor) => {
+                let page = Page::decode(cursor)?;
+                Page {
+                    offset: page.offset,
+                    limit: limit.unwrap_or(page.limit),
+                }
+            }
+            None => Page {
+                offset: offset.unwrap_or(0),
+                limit: limit.unwrap_or(DEFAULT_LIMIT),
+            },
+        };
+        if page.offset > MAX_OFFSET {
+            return Err(InvalidPage::Offset);
+        }
+        Ok(Page {
+            offset: page.offset,
+            limit: page.limit.clamp(1, MAX_LIMIT),
+        })
+    }
+
+    /// The page following this one, if there are hits left past it and it is
+    /// within `MAX_OFFSET`.
+    pub fn next(&self, total: usize) -> Option<Page> {
+        let offset = self.offset.saturating_add(self.limit);
+        (offset < total && offset <= MAX_OFFSET).then_some(Page {
+            offset,
+            limit: self.limit,
+        })
+    }
+
+    pub fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(format!("{CURSOR_PREFIX}:{}:{}", self.offset, self.limit))
+    }
+
+    pub fn decode(cursor: &str) -> Result<Page, InvalidPage> {
+        let bytes = URL_SAFE_NO_PAD
+            .decode(cursor)
+            .map_err(|_| InvalidPage::Cursor)?;
+        let raw = String::from_utf8(bytes).map_err(|_| InvalidPage::Cursor)?;
+        let mut parts = raw.split(':');
+        if parts.next() != Some(CURSOR_PREFIX) {
+            return Err(InvalidPage::Cursor);
+        }
+        let mut number = || -> Result<usize, InvalidPage> {
+            parts
+                .next()
+                .and_then(|part| part.parse().ok())
+                .ok_or(InvalidPage::Cursor)
+        };
+        let offset = number()?;
+        let limit = number()?;
+        if parts.next().is_some() || limit == 0 || offset > MAX_OFFSET {
+            return Err(InvalidPage::Cursor);
+        }
+        Ok(Page { offset, limit })
+    }
+
+    pub fn info(&self, total: usize) -> PageInfo {
+        PageInfo {
+            offset: self.offset,
+            limit: self.limit,
+            has_more: self.next(total).is_some(),
+        }
+    }
+}
+
+/// Paging details echoed back with every search response.
+#[derive(Debug, Serialize)]
+pub struct PageInfo {
+    pub offset: usize,
+    pub limit: usize,
+    pub has_more: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pagination::{InvalidPage, Page, DEFAULT_LIMIT, MAX_LIMIT, MAX_OFFSET};
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+
+    #[test]
+    fn test_resolve_defaults_and_clamps() {
+        let page = Page::resolve(None, None, None).unwrap();
+        assert_eq!(
+            page,
+            Page {
+                offset: 0,
+                limit: DEFAULT_LIMIT
+            }
+        );
+
+        let page = Page::resolve(Some(40), Some(10_000), None).unwrap();
+        assert_eq!(
+            page,
+            Page {
+                offset: 40,
+                limit: MAX_LIMIT
+            }
+        );
+
+        let page = Page::resolve(None, Some(0), None).unwrap();
+        assert_eq!(page.limit, 1);
+    }
+
+    #[test]
+    fn test_cursor_round_trip() {
+        let page = Page {
+            offset: 20,
+            limit: 20,
+        };
+        let next = page.next(100).unwrap();
+        let cursor = next.encode();
+        let resolved = Page::resolve(Some(0), None, Some(&cursor)).unwrap();
+        assert_eq!(
+            resolved,
+            Page {
+                offset: 40,
+                limit: 20
+            }
+        );
+        assert!(Page {
+            offset: 80,
+            limit: 20
+        }
+        .next(100)
+        .is_none());
+    }
+
+    #[test]
+    fn test_invalid_cursor() {
+        assert_eq!(Page::decode("not a cursor"), Err(InvalidPage::Cursor));
+        assert_eq!(Page::decode(""), Err(InvalidPage::Cursor));
+    }
+
+    #[test]
+    fn test_deep_offsets_are_refused() {
+        assert_eq!(
+            Page::resolve(Some(usize::MAX), None, None),
+            Err(InvalidPage::Offset)
+        );
+        assert!(Page::resolve(Some(MAX_OFFSET), None, None).is_ok());
+        let cursor = URL_SAFE_NO_PAD.encode(format!("v1:{}:10", usize::MAX));
+        assert_eq!(Page::decode(&cursor), Err(InvalidPage::Cursor));
+        assert_eq!(
+            Page::resolve(None, None, Some(&cursor)),
+            Err(InvalidPage::Cursor)
+        );
+        // No cursor is handed out past the deepest page.
+        let last = Page {
+            offset: MAX_OFFSET - 10,
+            limit: 20,
+        };
+        assert!(last.next(usize::MAX).is_none());
+    }
+}
@@ -0,0 +1,620 @@
+//! Versioning of the index and rebuilding it next to the one being served.
+//!
+//! Every index records the `SCHEMA_VERSION` and the analyzer it was built with in
+//! `INDEX_VERSION_FILE`. `open_index` refuses an index built differently, instead of
+//! failing on the first document or silently analyzing queries unlike the documents.
+//! Such an index has to be rebuilt with `reindex`. An index from before versioning
+//! does not say which analyzer built it, `adopt_index` records the one given.
+//!
+//! A reindex builds a new generation of the index in a directory next to
+//! `index_path`, e.g. `index.20231101100000000`, then points `index_path`, a symlink,
+//! at it. The symlink is replaced with a rename, which is atomic, so the index at
+//! `index_path` is always complete. A running server notices the new target and
+//! reopens it, moving its writer there. Older generations are only removed when asked
+//! to with `prune_generations`, which leaves alone the current and previous ones and
+//! any a writer still holds. A reindex that would lose what a writer accepts
+//! meanwhile is refused.
+use crate::config::Analyzer;
+use crate::import::{import_csv, RejectedRow};
+use crate::indexer::sync_from_postgres;
+use crate::sink::ArticleSink;
+use crate::{get_article_schema, register_tokenizer, SCHEMA_VERSION};
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgPool;
+use sqlx::types::chrono::{DateTime, Utc};
+use std::fs::File;
+#[cfg(unix)]
+use std::os::unix::fs::symlink;
+#[cfg(windows)]
+use std::os::windows::fs::symlink_dir as symlink;
+use std::path::{Path, PathBuf};
+use tantivy::directory::error::LockError;
+use tantivy::directory::{Directory, MmapDirectory, INDEX_WRITER_LOCK};
+use tantivy::Index;
+
+/// Name of the version file, dotfiles are left alone by tantivy's garbage collection.
+pub const INDEX_VERSION_FILE: &str = ".index_version.json";
+
+/// How an index was built, documents and queries must be analyzed alike.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexVersion {
+    pub schema_version: u32,
+    pub analyzer: Analyzer,
+}
+
+impl std::fmt::Display for IndexVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "schema version {} and the {} analyzer",
+            self.schema_version,
+            self.analyzer.name()
+        )
+    }
+}
+
+impl IndexVersion {
+    /// The version of the indexes built by this binary with `analyzer`.
+    pub fn current(analyzer: Analyzer) -> IndexVersion {
+        IndexVersion {
+            schema_version: SCHEMA_VERSION,
+            analyzer,
+        }
+    }
+
+    pub fn load(index: &Index) -> anyhow::Result<Option<IndexVersion>> {
+        let path = Path::new(INDEX_VERSION_FILE);
+        if !index.directory().exists(path)? {
+            return Ok(None);
+        }
+        let data = index.directory().atomic_read(path)?;
+        Ok(Some(serde_json::from_slice(&data)?))
+    }
+
+    pub fn save(&self, index: &Index) -> anyhow::Result<()> {
+        let data = serde_json::to_vec(self)?;
+        index
+            .directory()
+            .atomic_write(Path::new(INDEX_VERSION_FILE), &data)?;
+        Ok(())
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error(
+    "the index at {path} was built with {found}, this build expects {expected}; \
+     rebuild it with the `reindex` command"
+)]
+pub struct IndexMismatch {
+    pub path: PathBuf,
+    /// Description of the version found, the index may predate versioning.
+    pub found: String,
+    pub expected: IndexVersion,
+}
+
+/// Opens the index at `path`, creating it when the directory is empty, and checks
+/// that it was built by this version of the schema with `analyzer`.
+pub fn open_index(path: &Path, analyzer: Analyzer) -> anyhow::Result<Index> {
+    // A new index is a first generation, so reindexing never has to move it.
+    if let Err(e) = std::fs::symlink_metadata(path) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            return Err(e.into());
+        }
+        let generation = generation_path(path, Utc::now())?;
+        std::fs::create_dir_all(&generation)?;
+        link(path, &generation)?;
+    }
+    let directory = MmapDirectory::open(path)?;
+    let schema = get_article_schema();
+    let expected = IndexVersion::current(analyzer);
+    if !Index::exists(&directory)? {
+        let index = Index::create(directory, schema, Default::default())?;
+        register_tokenizer(&index, analyzer);
+        expected.save(&index)?;
+        return Ok(index);
+    }
+    let index = Index::open(directory)?;
+    register_tokenizer(&index, analyzer);
+    let mismatch = |found: String| IndexMismatch {
+        path: path.to_path_buf(),
+        found,
+        expected,
+    };
+    match IndexVersion::load(&index)? {
+        Some(version) if version != expected => Err(mismatch(version.to_string()).into()),
+        Some(_) if index.schema() != schema => {
+            Err(mismatch("a schema differing from its recorded version".to_string()).into())
+        }
+        Some(_) => Ok(index),
+        // Both analyzers are registered under the same name, the schema cannot tell.
+        None if index.schema() == schema => Err(mismatch(
+            "no recorded version, `adopt` records it if the analyzer is right".to_string(),
+        )
+        .into()),
+        None => Err(mismatch("an older, unversioned schema".to_string()).into()),
+    }
+}
+
+/// Records `analyzer` as the one an index from before versioning was built with, so
+/// `open_index` accepts it. Fails when the index is versioned or its schema is not
+/// the current one.
+pub fn adopt_index(path: &Path, analyzer: Analyzer) -> anyhow::Result<()> {
+    let index = Index::open(MmapDirectory::open(path)?)?;
+    let expected = IndexVersion::current(analyzer);
+    let found = match IndexVersion::load(&index)? {
+        Some(version) if version == expected => return Ok(()),
+        Some(version) => format!("{version}, it is already versioned"),
+        None if index.schema() == get_article_schema() => {
+            tracing::info!(
+                "recording {} as the version of {}",
+                expected,
+                path.display()
+            );
+            return expected.save(&index);
+        }
+        None => "an older, unversioned schema".to_string(),
+    };
+    Err(IndexMismatch {
+        path: path.to_path_buf(),
+        found,
+        expected,
+    }
+    .into())
+}
+
+/// Where the articles of a reindex come from.
+pub enum ReindexSource {
+    Postgres(PgPool),
+    /// A CSV file laid out like `mock_data.csv`.
+    Csv(PathBuf),
+}
+
+#[derive(Debug)]
+pub struct ReindexReport {
+    /// Directory of the new generation, `index_path` points to it.
+    pub generation: PathBuf,
+    /// Directory `index_path` pointed to before, empty when there was none.
+    pub previous: PathBuf,
+    pub indexed: usize,
+    /// Rows of the CSV file that could not be indexed.
+    pub rejected: Vec<RejectedRow>,
+}
+
+/// `index_path` followed by the time the generation was started, e.g.
+/// `index.20231101100000000`, so generations sort by age.
+fn generation_path(index_path: &Path, started: DateTime<Utc>) -> anyhow::Result<PathBuf> {
+    let name = index_path
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("invalid index path {}", index_path.display()))?;
+    let mut generation = name.to_os_string();
+    generation.push(format!(".{}", started.format("%Y%m%d%H%M%S%3f")));
+    Ok(index_path.with_file_name(generation))
+}
+
+/// Builds a new generation of the index from `source`, then swaps it in at
+/// `index_path`. Nothing is swapped if building fails.
+pub async fn reindex(
+    index_path: &Path,
+    analyzer: Analyzer,
+    source: &ReindexSource,
+    writer_memory: usize,
+) -> anyhow::Result<ReindexReport> {
+    check_writers(index_path, source)?;
+    let generation = generation_path(index_path, Utc::now())?;
+    std::fs::create_dir(&generation)?;
+    let built = build(&generation, analyzer, source, writer_memory)
+        .await
+        .and_then(|report| {
+            // A writer may have started on the index meanwhile.
+            check_writers(index_path, source)?;
+            Ok(report)
+        });
+    let report = match built {
+        Ok(report) => report,
+        Err(e) => {
+            std::fs::remove_dir_all(&generation)?;
+            return Err(e);
+        }
+    };
+    let previous = swap(index_path, &generation)?;
+    Ok(ReindexReport { previous, ..report })
+}
+
+async fn build(
+    generation: &Path,
+    analyzer: Analyzer,
+    source: &ReindexSource,
+    writer_memory: usize,
+) -> anyhow::Result<ReindexReport> {
+    let index = Index::create_in_dir(generation, get_article_schema())?;
+    register_tokenizer(&index, analyzer);
+    let (indexed, rejected) = match source {
+        ReindexSource::Postgres(pool) => (
+            sync_from_postgres(pool, &index, ArticleSink::open(&index, writer_memory)?).await?,
+            Vec::new(),
+        ),
+        ReindexSource::Csv(file) => {
+            let sink = ArticleSink::open(&index, writer_memory)?;
+            let report = import_csv(File::open(file)?, None, sink).await?;
+            (report.imported, report.rejected)
+        }
+    };
+    IndexVersion::current(analyzer).save(&index)?;
+    Ok(ReindexReport {
+        generation: generation.to_path_buf(),
+        previous: PathBuf::new(),
+        indexed,
+        rejected,
+    })
+}
+
+/// Points the `index_path` symlink at `generation` and returns the directory it
+/// pointed to. An index directory from before generations is moved aside first,
+/// which is the only moment `index_path` does not exist.
+fn swap(index_path: &Path, generation: &Path) -> anyhow::Result<PathBuf> {
+    let parent = generation.parent().unwrap_or(Path::new(""));
+    let previous = match std::fs::symlink_metadata(index_path) {
+        Ok(metadata) if metadata.file_type().is_symlink() => {
+            parent.join(std::fs::read_link(index_path)?)
+        }
+        Ok(_) => {
+            let mut previous = generation.as_os_str().to_os_string();
+            previous.push("-previous");
+            let previous = PathBuf::from(previous);
+            std::fs::rename(index_path, &previous)?;
+            tracing::warn!(
+                "moved the index directory {} to {}",
+                index_path.display(),
+                previous.display()
+            );
+            previous
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => PathBuf::new(),
+        Err(e) => return Err(e.into()),
+    };
+    link(index_path, generation)?;
+    Ok(previous)
+}
+
+/// Points `index_path` at `generation` with a symlink renamed over it.
+fn link(index_path: &Path, generation: &Path) -> std::io::Result<()> {
+    // Relative, so the directory holding the generations can be moved as a whole.
+    let target = generation.file_name().unwrap();
+    let mut link = generation.as_os_str().to_os_string();
+    link.push(".link");
+    let link = PathBuf::from(link);
+    symlink(target, &link)?;
+    std::fs::rename(&link, index_path)
+}
+
+/// Refuses to reindex an index a writer holds when its writes would be lost: a
+/// directory from before generations is moved away from under the writer, and the
+/// articles it writes during a rebuild from CSV are not in the file. Rebuilt from
+/// Postgres, the server indexes the rows written meanwhile once it serves the new
+/// generation.
+fn check_writers(index_path: &Path, source: &ReindexSource) -> anyhow::Result<()> {
+    let is_symlink = match std::fs::symlink_metadata(index_path) {
+        Ok(metadata) => metadata.file_type().is_symlink(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    if !is_locked(index_path)? {
+        return Ok(());
+    }
+    if !is_symlink {
+        anyhow::bail!(
+            "{} is locked by a writer, stop the server accepting writes while this first \
+             reindex moves the index directory",
+            index_path.display()
+        );
+    }
+    if let ReindexSource::Csv(_) = source {
+        anyhow::bail!(
+            "{} is locked by a writer, the articles it writes would not be in the rebuilt \
+             index: reindex from the database or stop the server accepting writes",
+            index_path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Whether a writer, e.g. the one of a server accepting writes, holds the index in `path`.
+/// Servers that only read hold no lock.
+fn is_locked(path: &Path) -> anyhow::Result<bool> {
+    match MmapDirectory::open(path)?.acquire_lock(&INDEX_WRITER_LOCK) {
+        Ok(_lock) => Ok(false),
+        Err(LockError::LockBusy) => Ok(true),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Removes the generations of `index_path` other than `keep` and those locked by a
+/// writer, and returns the removed directories.
+pub fn prune_generations(index_path: &Path, keep: &[&Path]) -> anyhow::Result<Vec<PathBuf>> {
+    let mut removed = Vec::new();
+    let parent = match index_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let prefix = format!("{}.", index_path.file_name().unwrap().to_string_lossy());
+    for entry in std::fs::read_dir(parent)? {
+        let entry = entry?;
+        let is_generation =
+            entry.file_name().to_string_lossy().starts_with(&prefix) && entry.file_type()?.is_dir();
+        let kept = keep
+            .iter()
+            .any(|kept| kept.file_name() == Some(entry.file_name().as_os_str()));
+        if !is_generation || kept {
+            continue;
+        }
+        if is_locked(&entry.path())? {
+            tracing::warn!(
+                "keeping the old index {}, a writer holds it",
+                entry.path().display()
+            );
+            continue;
+        }
+        tracing::info!("removing the old index {}", entry.path().display());
+        std::fs::remove_dir_all(entry.path())?;
+        removed.push(entry.path());
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::article::find_indexed_article;
+    use crate::config::{Analyzer, CommitConfig};
+    use crate::db::TestDatabase;
+    use crate::feed::{feed_state_path, FeedState};
+    use crate::reindex::{
+        adopt_index, is_locked, open_index, prune_generations, reindex, IndexMismatch,
+        IndexVersion, ReindexSource, INDEX_VERSION_FILE,
+    };
+    use crate::writer::IndexOp;
+    use crate::{test_article, AppState};
+    use std::path::Path;
+    use tantivy::schema::{Schema, TEXT};
+    use tantivy::Index;
+
+    const TOKEN: &str = "0123456789abcdef";
+
+    fn num_docs(path: &Path) -> u64 {
+        let index = open_index(path, Analyzer::default()).unwrap();
+        index.reader().unwrap().searcher().num_docs()
+    }
+
+    #[test]
+    fn test_open_index_checks_the_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index");
+        let index = open_index(&path, Analyzer::Vietnamese).unwrap();
+        assert_eq!(
+            IndexVersion::load(&index).unwrap(),
+            Some(IndexVersion::current(Analyzer::Vietnamese))
+        );
+        drop(index);
+        assert!(open_index(&path, Analyzer::Vietnamese).is_ok());
+        let error = open_index(&path, Analyzer::VietnameseBigrams).unwrap_err();
+        assert!(error.downcast_ref::<IndexMismatch>().is_some());
+
+        // An index from before versioning is only opened once adopted.
+        std::fs::remove_file(path.join(INDEX_VERSION_FILE)).unwrap();
+        let error = open_index(&path, Analyzer::Vietnamese).unwrap_err();
+        assert!(error.downcast_ref::<IndexMismatch>().is_some());
+        adopt_index(&path, Analyzer::Vietnamese).unwrap();
+        assert!(open_index(&path, Analyzer::Vietnamese).is_ok());
+        assert!(open_index(&path, Analyzer::VietnameseBigrams).is_err());
+        assert!(adopt_index(&path, Analyzer::VietnameseBigrams).is_err());
+
+        let path = dir.path().join("older");
+        std::fs::create_dir(&path).unwrap();
+        let mut schema = Schema::builder();
+        schema.add_text_field("title", TEXT);
+        Index::create_in_dir(&path, schema.build()).unwrap();
+        let error = open_index(&path, Analyzer::Vietnamese).unwrap_err();
+        assert!(error.downcast_ref::<IndexMismatch>().is_some());
+        assert!(adopt_index(&path, Analyzer::Vietnamese).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reindex_swaps_generations() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index");
+        // A served index, from before generations.
+        std::fs::create_dir(&path).unwrap();
+        let index = open_index(&path, Analyzer::default()).unwrap();
+        let mut writer = index.writer(15_000_000).unwrap();
+        writer
+            .add_document(test_article("1", "Bão số 9", 1).to_document(&index.schema()))
+            .unwrap();
+        writer.commit().unwrap();
+        drop(writer);
+        let reader = index.reader().unwrap();
+
+        let csv = Path::new(env!("CARGO_MANIFEST_DIR")).join("mock_data.csv");
+        let report = reindex(
+            &path,
+            Analyzer::default(),
+            &ReindexSource::Csv(csv.clone()),
+            15_000_000,
+        )
+        .await
+        .unwrap();
+        assert!(report.indexed > 1);
+        assert!(std::fs::symlink_metadata(&path)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(num_docs(&path), report.indexed as u64);
+        // The index opened before the swap still serves.
+        assert_eq!(reader.searcher().num_docs(), 1);
+
+        let second = reindex(
+            &path,
+            Analyzer::default(),
+            &ReindexSource::Csv(csv.clone()),
+            15_000_000,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            std::fs::read_link(&path).unwrap(),
+            second.generation.file_name().unwrap()
+        );
+        let third = reindex(
+            &path,
+            Analyzer::default(),
+            &ReindexSource::Csv(csv),
+            15_000_000,
+        )
+        .await
+        .unwrap();
+        assert_eq!(num_docs(&path), third.indexed as u64);
+        assert_eq!(third.previous, second.generation);
+
+        // The current and the previous generations are kept, as well as those a writer holds.
+        assert!(report.previous.exists());
+        let feed_state = feed_state_path(&path);
+        FeedState::default().save(&feed_state).unwrap();
+        let writer = Index::open_in_dir(&report.generation)
+            .unwrap()
+            .writer(15_000_000)
+            .unwrap();
+        let keep = [third.generation.as_path(), &third.previous];
+        let removed = prune_generations(&path, &keep).unwrap();
+        assert_eq!(removed, std::slice::from_ref(&report.previous));
+        drop(writer);
+        let removed = prune_generations(&path, &keep).unwrap();
+        assert_eq!(removed, std::slice::from_ref(&report.generation));
+        assert!(second.generation.exists() && third.generation.exists());
+        // The feed validators are kept beside the generations.
+        assert!(feed_state.exists());
+
+        let missing = ReindexSource::Csv(dir.path().join("missing.csv"));
+        assert!(reindex(&path, Analyzer::default(), &missing, 15_000_000)
+            .await
+            .is_err());
+        assert_eq!(
+            std::fs::read_link(&path).unwrap(),
+            third.generation.file_name().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reindex_refuses_to_lose_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let csv = ReindexSource::Csv(Path::new(env!("CARGO_MANIFEST_DIR")).join("mock_data.csv"));
+        // A directory from before generations would move away from under its writer.
+        let legacy = dir.path().join("legacy");
+        std::fs::create_dir(&legacy).unwrap();
+        let state = AppState::new(open_index(&legacy, Analyzer::default()).unwrap())
+            .unwrap()
+            .with_writer(TOKEN.to_string(), 15_000_000, CommitConfig::default())
+            .unwrap();
+        let error = reindex(&legacy, Analyzer::default(), &csv, 15_000_000)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("first reindex"), "{error}");
+        assert!(std::fs::symlink_metadata(&legacy).unwrap().is_dir());
+        state.writer().unwrap().shutdown().await.unwrap();
+        assert!(reindex(&legacy, Analyzer::default(), &csv, 15_000_000)
+            .await
+            .is_ok());
+
+        // A CSV file does not hold what the writer of a generation accepts meanwhile.
+        let path = dir.path().join("index");
+        let state = AppState::new(open_index(&path, Analyzer::default()).unwrap())
+            .unwrap()
+            .with_writer(TOKEN.to_string(), 15_000_000, CommitConfig::default())
+            .unwrap();
+        let served = std::fs::read_link(&path).unwrap();
+        let error = reindex(&path, Analyzer::default(), &csv, 15_000_000)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("database"), "{error}");
+        assert_eq!(std::fs::read_link(&path).unwrap(), served);
+
+        // A server that only reads serves the new generation once reopened.
+        state.writer().unwrap().shutdown().await.unwrap();
+        let state = AppState::new(state.index()).unwrap();
+        let report = reindex(&path, Analyzer::default(), &csv, 15_000_000)
+            .await
+            .unwrap();
+        assert_eq!(state.reader().searcher().num_docs(), 0);
+        let indexed = state
+            .reopen(open_index(&path, Analyzer::default()).unwrap(), 15_000_000)
+            .await
+            .unwrap();
+        assert_eq!(indexed, 0);
+        assert_eq!(state.reader().searcher().num_docs(), report.indexed as u64);
+    }
+
+    #[tokio::test]
+    async fn test_server_writes_across_a_swap() {
+        let Some(db) = TestDatabase::create().await else {
+            return;
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index");
+        let commit = CommitConfig {
+            interval_ms: 10,
+            ..CommitConfig::default()
+        };
+        let state = AppState::new(open_index(&path, Analyzer::default()).unwrap())
+            .unwrap()
+            .with_pool(db.pool.clone())
+            .with_writer(TOKEN.to_string(), 15_000_000, commit)
+            .unwrap();
+        // Written like the write API does, to Postgres then through the writer.
+        async fn write(state: &AppState, id: &str) {
+            let article = test_article(id, "Bão số 9", 1);
+            article.save(state.pool.as_ref().unwrap()).await.unwrap();
+            let writer = state.writer().unwrap();
+            writer.apply(vec![IndexOp::Upsert(article)]).await.unwrap();
+        }
+        write(&state, "1").await;
+        let source = ReindexSource::Postgres(db.pool.clone());
+        let report = reindex(&path, Analyzer::default(), &source, 15_000_000)
+            .await
+            .unwrap();
+        assert_eq!(report.indexed, 1);
+        // Accepted by the writer of the previous generation.
+        write(&state, "2").await;
+
+        let indexed = state
+            .reopen(open_index(&path, Analyzer::default()).unwrap(), 15_000_000)
+            .await
+            .unwrap();
+        assert!(indexed >= 1);
+        // The previous writer committed and released its generation.
+        assert!(!is_locked(&report.previous).unwrap());
+        assert_eq!(num_docs(&report.previous), 2);
+        write(&state, "3").await;
+        assert_eq!(state.reader().searcher().num_docs(), 3);
+        assert!(find_indexed_article(&state, "2").unwrap().is_some());
+        state.writer().unwrap().shutdown().await.unwrap();
+        assert_eq!(num_docs(&path), 3);
+        db.destroy().await;
+    }
+
+    #[tokio::test]
+    async fn test_reindex_from_postgres() {
+        let Some(db) = TestDatabase::create().await else {
+            return;
+        };
+        test_article("1", "Bão số 9", 1)
+            .save(&db.pool)
+            .await
+            .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index");
+        let source = ReindexSource::Postgres(db.pool.clone());
+        let report = reindex(&path, Analyzer::default(), &source, 15_000_000)
+            .await
+            .unwrap();
+        assert_eq!(report.indexed, 1);
+        assert_eq!(num_docs(&path), 1);
+        db.destroy().await;
+    }
+}
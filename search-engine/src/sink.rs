@@ -0,0 +1,210 @@
+//! Where the articles found by `import`, `crawl` and `feeds` are written.
+//!
+//! Tantivy allows a single `IndexWriter` per index, and a server accepting writes
+//! holds it for as long as it runs. Those commands either open their own writer,
+//! which fails while such a server runs, or send their articles to the bulk
+//! endpoint of the server so its writer stays the only one. Within the server, the
+//! Postgres sync goes through that writer too.
+use crate::article::Article;
+use crate::indexer::upsert_article;
+use crate::write::BulkResponse;
+use crate::writer::{IndexOp, WriterHandle};
+use anyhow::Context;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Client, Url};
+use std::time::Duration;
+use tantivy::directory::error::LockError;
+use tantivy::{Index, IndexWriter, TantivyError};
+
+/// Size of the bulk requests sent to a server, well under its `BULK_BODY_LIMIT`.
+const MAX_BATCH_BYTES: usize = 8 * 1024 * 1024;
+
+pub enum ArticleSink {
+    /// A writer of the index opened by the command.
+    Index(IndexWriter),
+    /// The write API of a running server.
+    Server(ServerSink),
+    /// The writer of this server, the operations are sent on commit.
+    Writer(WriterHandle, Vec<IndexOp>),
+}
+
+pub struct ServerSink {
+    client: Client,
+    bulk_url: Url,
+    api_token: String,
+    /// Newline delimited JSON articles not sent yet.
+    batch: String,
+}
+
+impl ArticleSink {
+    /// Opens a writer of `index`, fails while a server accepting writes runs.
+    pub fn open(index: &Index, writer_memory: usize) -> anyhow::Result<ArticleSink> {
+        match index.writer(writer_memory) {
+            Ok(writer) => Ok(ArticleSink::Index(writer)),
+            Err(e @ TantivyError::LockFailure(LockError::LockBusy, _)) => Err(e).context(
+                "the index is locked by another writer, use --server to send the articles \
+                 to the server accepting writes",
+            ),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Sends the articles to the server at `server`, authenticated with `api_token`.
+    pub fn server(server: &Url, api_token: String) -> anyhow::Result<ArticleSink> {
+        // A bulk request only returns once the server committed it.
+        let client = Client::builder()
+            .timeout(Duration::from_secs(300))
+            .build()?;
+        Ok(ArticleSink::Server(ServerSink {
+            client,
+            bulk_url: server.join("/api/articles/bulk")?,
+            api_token,
+            batch: String::new(),
+        }))
+    }
+
+    /// Creates the article or replaces the one with the same id, once committed.
+    pub async fn upsert(&mut self, article: &Article) -> anyhow::Result<()> {
+        match self {
+            ArticleSink::Index(writer) => {
+                upsert_article(writer, article)?;
+            }
+            ArticleSink::Server(server) => {
+                let line = serde_json::to_string(&serde_json::json!({
+                    "id": article.id,
+                    "title": article.title,
+                    "summary": article.summary,
+                    "content": article.content,
+                    "url": article.url,
+                    "created_time": article.timestamp,
+                }))?;
+                if server.batch.len() + line.len() > MAX_BATCH_BYTES {
+                    server.send().await?;
+                }
+                server.batch.push_str(&line);
+                server.batch.push('\n');
+            }
+            ArticleSink::Writer(_, ops) => ops.push(IndexOp::Upsert(article.clone())),
+        }
+        Ok(())
+    }
+
+    /// Makes the articles upserted so far searchable.
+    pub async fn commit(&mut self) -> anyhow::Result<()> {
+        match self {
+            ArticleSink::Index(writer) => {
+                writer.commit()?;
+            }
+            ArticleSink::Server(server) => server.send().await?,
+            ArticleSink::Writer(writer, ops) => {
+                if !ops.is_empty() {
+                    writer.apply(std::mem::take(ops)).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Commits, and waits for the merges of the writer to end.
+    pub async fn finish(mut self) -> anyhow::Result<()> {
+        self.commit().await?;
+        if let ArticleSink::Index(writer) = self {
+            writer.wait_merging_threads()?;
+        }
+        Ok(())
+    }
+}
+
+impl ServerSink {
+    async fn send(&mut self) -> anyhow::Result<()> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let response = self
+            .client
+            .post(self.bulk_url.clone())
+            .bearer_auth(&self.api_token)
+            .header(CONTENT_TYPE, "application/x-ndjson")
+            .body(std::mem::take(&mut self.batch))
+            .send()
+            .await?;
+        let status = response.status();
+        let body = response.bytes().await?;
+        if !status.is_success() {
+            anyhow::bail!(
+                "{} answered {}: {}",
+                self.bulk_url,
+                status,
+                String::from_utf8_lossy(&body)
+            );
+        }
+        // The articles were checked before being sent, the server should take them all.
+        let response: BulkResponse = serde_json::from_slice(&body)?;
+        if let Some(rejected) = response.rejected.first() {
+            anyhow::bail!(
+                "{} rejected {} articles, the first on line {}: {}",
+                self.bulk_url,
+                response.rejected.len(),
+                rejected.line,
+                rejected.reason
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::CommitConfig;
+    use crate::sink::ArticleSink;
+    use crate::write::bulk_articles;
+    use crate::{test_article, test_state};
+    use axum::routing::post;
+    use axum::Router;
+    use reqwest::Url;
+
+    const TOKEN: &str = "0123456789abcdef";
+
+    #[tokio::test]
+    async fn test_server_sink_writes_through_the_server() {
+        let commit = CommitConfig {
+            interval_ms: 10,
+            ..CommitConfig::default()
+        };
+        let state = test_state(&[])
+            .with_writer(TOKEN.to_string(), 15_000_000, commit)
+            .unwrap();
+        // The server holds the only writer.
+        let error = ArticleSink::open(&state.index(), 15_000_000).err().unwrap();
+        assert!(error.to_string().contains("--server"));
+
+        let router = Router::new()
+            .route("/api/articles/bulk", post(bulk_articles))
+            .with_state(state.clone());
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(router.into_make_service());
+        tokio::spawn(server);
+        let url = Url::parse(&format!("http://{address}")).unwrap();
+
+        let mut sink = ArticleSink::server(&url, TOKEN.to_string()).unwrap();
+        sink.upsert(&test_article("1", "Bài số 1", 1))
+            .await
+            .unwrap();
+        sink.upsert(&test_article("2", "Bài số 2", 1))
+            .await
+            .unwrap();
+        assert_eq!(state.reader().searcher().num_docs(), 0);
+        sink.finish().await.unwrap();
+        assert_eq!(state.reader().searcher().num_docs(), 2);
+
+        let mut sink = ArticleSink::server(&url, "wrong token 0123".to_string()).unwrap();
+        sink.upsert(&test_article("3", "Bài số 3", 1))
+            .await
+            .unwrap();
+        let error = sink.commit().await.unwrap_err();
+        assert!(error.to_string().contains("401"), "{error}");
+    }
+}
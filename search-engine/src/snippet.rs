@@ -0,0 +1,128 @@
+//! Highlighted fragments explaining why an article matched a query.
+use serde::{Deserialize, Serialize};
+use tantivy::query::Query;
+use tantivy::schema::Schema;
+use tantivy::{Document, Searcher, SnippetGenerator};
+
+/// Fragments longer than this are clamped, whatever the request asks for.
+pub const MAX_FRAGMENT_LENGTH: usize = 1000;
+
+/// How the highlighted fragments of a hit should be rendered.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SnippetOptions {
+    /// Maximum number of characters of a summary or content fragment,
+    /// titles are always highlighted in full.
+    pub fragment_length: usize,
+    /// Marker inserted before every matching term.
+    pub pre_tag: String,
+    /// Marker inserted after every matching term.
+    pub post_tag: String,
+}
+
+impl Default for SnippetOptions {
+    fn default() -> Self {
+        SnippetOptions {
+            fragment_length: 150,
+            pre_tag: "<b>".to_string(),
+            post_tag: "</b>".to_string(),
+        }
+    }
+}
+
+/// Highlighted fragments of a hit, a field is only present when it matched.
+#[derive(Debug, Default, Serialize)]
+pub struct Snippets {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+}
+
+/// Builds the `Snippets` of every hit of one query.
+pub struct Highlighter {
+    title: SnippetGenerator,
+    summary: SnippetGenerator,
+    content: SnippetGenerator,
+    options: SnippetOptions,
+}
+
+impl Highlighter {
+    pub fn new(
+        searcher: &Searcher,
+        query: &dyn Query,
+        schema: &Schema,
+        options: &SnippetOptions,
+    ) -> tantivy::Result<Self> {
+        let fragment_length = options.fragment_length.clamp(1, MAX_FRAGMENT_LENGTH);
+        let generator = |name: &str, max_num_chars: usize| -> tantivy::Result<SnippetGenerator> {
+            let mut generator = SnippetGenerator::create(searcher, query, schema.get_field(name)?)?;
+            generator.set_max_num_chars(max_num_chars);
+            Ok(generator)
+        };
+        Ok(Highlighter {
+            title: generator("title", MAX_FRAGMENT_LENGTH)?,
+            summary: generator("summary", fragment_length)?,
+            content: generator("content", fragment_length)?,
+            options: options.clone(),
+        })
+    }
+
+    pub fn snippets(&self, doc: &Document) -> Snippets {
+        Snippets {
+            title: self.highlight(&self.title, doc),
+            summary: self.highlight(&self.summary, doc),
+            content: self.highlight(&self.content, doc),
+        }
+    }
+
+    fn highlight(&self, generator: &SnippetGenerator, doc: &Document) -> Option<String> {
+        let mut snippet = generator.snippet_from_doc(doc);
+        if snippet.is_empty() {
+            return None;
+        }
+        snippet.set_snippet_prefix_postfix(&self.options.pre_tag, &self.options.post_tag);
+        Some(snippet.to_html())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::article::Article;
+    use crate::snippet::{Highlighter, SnippetOptions};
+    use crate::{test_article, test_index};
+    use tantivy::collector::TopDocs;
+    use tantivy::query::QueryParser;
+
+    #[test]
+    fn test_highlight_matching_fields() {
+        let index = test_index(&[Article {
+            summary: "Người dân sơ tán".to_string(),
+            content: "Cơn bão mạnh nhất năm đã đổ bộ vào miền Trung".to_string(),
+            ..test_article("1", "Bão số 9 đổ bộ", 1)
+        }]);
+        let schema = index.schema();
+        let searcher = index.reader().unwrap().searcher();
+        let fields = ["title", "summary", "content"].map(|name| schema.get_field(name).unwrap());
+        let query = QueryParser::for_index(&index, fields.to_vec())
+            .parse_query("bão")
+            .unwrap();
+        let options = SnippetOptions {
+            fragment_length: 20,
+            pre_tag: "[".to_string(),
+            post_tag: "]".to_string(),
+        };
+        let highlighter = Highlighter::new(&searcher, &query, &schema, &options).unwrap();
+        let top_docs = searcher.search(&query, &TopDocs::with_limit(1)).unwrap();
+        let doc = searcher.doc(top_docs[0].1).unwrap();
+
+        let snippets = highlighter.snippets(&doc);
+        assert_eq!(snippets.title.as_deref(), Some("[Bão] số 9 đổ bộ"));
+        assert!(snippets.summary.is_none());
+        let content = snippets.content.unwrap();
+        assert!(content.starts_with("Cơn [bão]"));
+        assert!(content.chars().count() <= 20 + 2);
+    }
+}
@@ -0,0 +1,226 @@
+//! "Did you mean" suggestions for queries with few hits.
+//!
+//! The vocabulary is read from the term dictionaries of the title and summary
+//! fields and grouped by folded form, so a word typed with wrong or missing tone
+//! marks is corrected to the most frequent spelling sharing its letters, e.g.
+//! "hả nôi" to "hà nội". Words absent from the vocabulary are replaced by the
+//! closest frequent term within the edit distance allowed for their length.
+use crate::config::FuzzyConfig;
+use crate::fuzzy::edit_distance;
+use crate::vietnamese::fold;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use tantivy::{Searcher, SearcherGeneration};
+
+/// Queries with fewer hits than this get a suggestion.
+pub const SUGGEST_BELOW: usize = 3;
+/// Fields whose terms make up the vocabulary, content is left out as it is far larger.
+const VOCABULARY_FIELDS: [&str; 2] = ["title", "summary"];
+
+/// Indexed terms grouped by folded form, with their document frequency.
+#[derive(Debug, Default)]
+pub struct Vocabulary {
+    spellings: HashMap<String, HashMap<String, u64>>,
+}
+
+/// The vocabulary of the last searcher generation, so it is only read again
+/// once a commit or reload changed the segments.
+#[derive(Default)]
+pub struct VocabularyCache {
+    cached: RwLock<Option<(SearcherGeneration, Arc<Vocabulary>)>>,
+}
+
+impl VocabularyCache {
+    pub fn get(&self, searcher: &Searcher) -> tantivy::Result<Arc<Vocabulary>> {
+        let generation = searcher.generation();
+        if let Some((cached, vocabulary)) = &*self.cached.read().unwrap() {
+            if cached == generation {
+                return Ok(vocabulary.clone());
+            }
+        }
+        let mut cached = self.cached.write().unwrap();
+        // Another query may have read it while this one waited for the lock.
+        if let Some((cached, vocabulary)) = &*cached {
+            if cached == generation {
+                return Ok(vocabulary.clone());
+            }
+        }
+        let vocabulary = Arc::new(Vocabulary::from_searcher(searcher)?);
+        *cached = Some((generation.clone(), vocabulary.clone()));
+        Ok(vocabulary)
+    }
+}
+
+impl Vocabulary {
+    pub fn from_searcher(searcher: &Searcher) -> tantivy::Result<Vocabulary> {
+        let schema = searcher.schema();
+        let mut vocabulary = Vocabulary::default();
+        for name in VOCABULARY_FIELDS {
+            let field = schema.get_field(name)?;
+            for segment_reader in searcher.segment_readers() {
+                let inverted_index = segment_reader.inverted_index(field)?;
+                let mut terms = inverted_index.terms().stream()?;
+                while terms.advance() {
+                    if let Ok(term) = std::str::from_utf8(terms.key()) {
+                        vocabulary.add(term, terms.value().doc_freq as u64);
+                    }
+                }
+            }
+        }
+        Ok(vocabulary)
+    }
+
+    fn add(&mut self, term: &str, doc_freq: u64) {
+        *self
+            .spellings
+            .entry(fold(term))
+            .or_default()
+            .entry(term.to_string())
+            .or_default() += doc_freq;
+    }
+
+    fn contains(&self, word: &str) -> bool {
+        self.spellings
+            .get(&fold(word))
+            .is_some_and(|spellings| spellings.contains_key(word))
+    }
+
+    /// The spelling of `folded` to suggest, accented when `accented` and one exists.
+    fn spelling(&self, folded: &str, accented: bool) -> Option<(&str, u64)> {
+        let spellings = self.spellings.get(folded)?;
+        spellings
+            .iter()
+            .filter(|(term, _)| !accented || spellings.len() == 1 || term.as_str() != folded)
+            .max_by(|a, b| a.1.cmp(b.1).then_with(|| b.0.cmp(a.0)))
+            .map(|(term, doc_freq)| (term.as_str(), *doc_freq))
+    }
+
+    /// The correction of a lowercase word, `None` when it is already indexed or
+    /// nothing close enough is.
+    pub fn correct(&self, word: &str, config: &FuzzyConfig) -> Option<String> {
+        if self.contains(word) {
+            return None;
+        }
+        let folded = fold(word);
+        let accented = folded != word;
+        // Only the tone marks are wrong.
+        if let Some((term, _)) = self.spelling(&folded, accented) {
+            return Some(term.to_string());
+        }
+        let max_distance = edit_distance(folded.chars().count(), config) as usize;
+        if max_distance == 0 {
+            return None;
+        }
+        let length = folded.chars().count();
+        self.spellings
+            .keys()
+            .filter(|key| key.chars().count().abs_diff(length) <= max_distance)
+            .filter_map(|key| {
+                let distance = damerau_levenshtein(&folded, key, max_distance)?;
+                let (term, doc_freq) = self.spelling(key, accented)?;
+                Some((distance, doc_freq, term))
+            })
+            .min_by(|a, b| {
+                a.0.cmp(&b.0)
+                    .then_with(|| b.1.cmp(&a.1))
+                    .then_with(|| a.2.cmp(b.2))
+            })
+            .map(|(_, _, term)| term.to_string())
+    }
+
+    /// `query` with every misspelled word corrected, `None` when nothing was.
+    /// Field names, operators and punctuation are kept as they are.
+    pub fn suggest(&self, query: &str, config: &FuzzyConfig) -> Option<String> {
+        let mut suggestion = String::with_capacity(query.len());
+        let mut corrected = false;
+        let mut rest = query;
+        while let Some(start) = rest.find(char::is_alphanumeric) {
+            suggestion.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let end = rest
+                .find(|c: char| !c.is_alphanumeric())
+                .unwrap_or(rest.len());
+            let word = &rest[..end];
+            rest = &rest[end..];
+            let is_syntax = rest.starts_with(':') || ["AND", "OR", "NOT", "IN"].contains(&word);
+            match self.correct(&word.to_lowercase(), config) {
+                Some(correction) if !is_syntax => {
+                    suggestion.push_str(&correction);
+                    corrected = true;
+                }
+                _ => suggestion.push_str(word),
+            }
+        }
+        suggestion.push_str(rest);
+        corrected.then_some(suggestion)
+    }
+}
+
+/// Optimal string alignment distance between `a` and `b`, where swapping two
+/// adjacent characters is one edit. `None` when it exceeds `max`.
+pub fn damerau_levenshtein(a: &str, b: &str, max: usize) -> Option<usize> {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut previous_row: Vec<usize> = Vec::new();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        let mut next_row = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            next_row[j] = (row[j] + 1).min(next_row[j - 1] + 1).min(row[j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                next_row[j] = next_row[j].min(previous_row[j - 2] + 1);
+            }
+        }
+        if next_row.iter().all(|&distance| distance > max) {
+            return None;
+        }
+        previous_row = std::mem::replace(&mut row, next_row);
+    }
+    Some(row[b.len()]).filter(|&distance| distance <= max)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::FuzzyConfig;
+    use crate::spelling::{damerau_levenshtein, Vocabulary};
+
+    #[test]
+    fn test_damerau_levenshtein() {
+        assert_eq!(damerau_levenshtein("thanh", "thnah", 2), Some(1));
+        assert_eq!(damerau_levenshtein("bong", "bongda", 2), Some(2));
+        assert_eq!(damerau_levenshtein("bong", "bongda", 1), None);
+        assert_eq!(damerau_levenshtein("", "ab", 2), Some(2));
+    }
+
+    #[test]
+    fn test_suggest() {
+        let mut vocabulary = Vocabulary::default();
+        for (term, doc_freq) in [
+            ("hà", 40),
+            ("ha", 50),
+            ("hạ", 5),
+            ("nội", 30),
+            ("noi", 35),
+            ("thành", 20),
+            ("thanh", 25),
+            ("phố", 10),
+            ("pho", 12),
+        ] {
+            vocabulary.add(term, doc_freq);
+        }
+        let config = FuzzyConfig::default();
+        assert_eq!(
+            vocabulary.suggest("hả nôi", &config).as_deref(),
+            Some("hà nội")
+        );
+        assert_eq!(
+            vocabulary
+                .suggest("title:thnah AND \"phô\"", &config)
+                .as_deref(),
+            Some("title:thanh AND \"phố\"")
+        );
+        assert_eq!(vocabulary.suggest("ha noi", &config), None);
+        assert_eq!(vocabulary.suggest("xyz", &config), None);
+    }
+}
@@ -0,0 +1,238 @@
+//! Search-as-you-type completions.
+//!
+//! The last word being typed is completed from the term dictionary of the title
+//! field, walking only the terms starting with it, most frequent first. Past
+//! queries that had hits are completed as a whole, most searched first. Both are
+//! cheap enough to answer every keystroke.
+use crate::error::Error;
+use crate::vietnamese::fold;
+use crate::AppState;
+use axum::extract::{Query, State};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use tantivy::Searcher;
+
+/// Completions returned when the request does not ask for a number.
+pub const DEFAULT_SUGGESTIONS: usize = 8;
+/// Most completions a single request may ask for.
+pub const MAX_SUGGESTIONS: usize = 20;
+/// Title terms looked at for a single prefix, so short prefixes stay fast.
+const MAX_SCANNED_TERMS: usize = 2000;
+/// Distinct queries remembered, the least searched are forgotten past it.
+const MAX_LOGGED_QUERIES: usize = 10_000;
+
+/// How often each query was searched, keyed by its folded form so "ha noi"
+/// completes to "hà nội".
+#[derive(Debug, Default)]
+pub struct QueryLog {
+    queries: Mutex<BTreeMap<String, LoggedQuery>>,
+}
+
+#[derive(Debug)]
+struct LoggedQuery {
+    text: String,
+    count: u64,
+}
+
+/// Lowercases `text` and collapses its whitespace.
+fn normalize(text: &str) -> String {
+    text.split_whitespace()
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl QueryLog {
+    pub fn record(&self, query: &str) {
+        let text = normalize(query);
+        if text.is_empty() {
+            return;
+        }
+        let mut queries = self.queries.lock().unwrap();
+        queries
+            .entry(fold(&text))
+            .or_insert_with(|| LoggedQuery { text, count: 0 })
+            .count += 1;
+        if queries.len() > MAX_LOGGED_QUERIES {
+            // Forget the queries searched once, then halve the counts so old
+            // popular queries eventually make room for new ones.
+            queries.retain(|_, query| query.count > 1);
+            for query in queries.values_mut() {
+                query.count /= 2;
+            }
+        }
+    }
+
+    /// The most searched queries starting with `prefix`, with their count.
+    pub fn complete(&self, prefix: &str, limit: usize) -> Vec<(String, u64)> {
+        let prefix = fold(&normalize(prefix));
+        let queries = self.queries.lock().unwrap();
+        let mut completions: Vec<(String, u64)> = queries
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .map(|(_, query)| (query.text.clone(), query.count))
+            .collect();
+        completions.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        completions.truncate(limit);
+        completions
+    }
+}
+
+/// The most frequent title terms starting with `prefix`, with their document frequency.
+pub fn complete_term(
+    searcher: &Searcher,
+    prefix: &str,
+    limit: usize,
+) -> tantivy::Result<Vec<(String, u64)>> {
+    let title = searcher.schema().get_field("title")?;
+    let mut doc_freqs: HashMap<String, u64> = HashMap::new();
+    for segment_reader in searcher.segment_readers() {
+        let inverted_index = segment_reader.inverted_index(title)?;
+        let mut terms = inverted_index
+            .terms()
+            .range()
+            .ge(prefix.as_bytes())
+            .into_stream()?;
+        let mut scanned = 0;
+        while scanned < MAX_SCANNED_TERMS && terms.advance() {
+            let Ok(term) = std::str::from_utf8(terms.key()) else {
+                continue;
+            };
+            if !term.starts_with(prefix) {
+                break;
+            }
+            *doc_freqs.entry(term.to_string()).or_default() += terms.value().doc_freq as u64;
+            scanned += 1;
+        }
+    }
+    let mut completions: Vec<(String, u64)> = doc_freqs.into_iter().collect();
+    completions.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    completions.truncate(limit);
+    Ok(completions)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SuggestionSource {
+    /// A query searched before.
+    Query,
+    /// The text typed with its last word completed from article titles.
+    Title,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Suggestion {
+    pub text: String,
+    pub source: SuggestionSource,
+    /// Times the query was searched, or number of titles holding the completed word.
+    pub count: u64,
+}
+
+/// Past queries first, then title completions of the last word, without duplicates.
+pub fn suggest(app_state: &AppState, text: &str, limit: usize) -> tantivy::Result<Vec<Suggestion>> {
+    let normalized = normalize(text);
+    if normalized.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut suggestions: Vec<Suggestion> = app_state
+        .query_log
+        .complete(&normalized, limit)
+        .into_iter()
+        .map(|(text, count)| Suggestion {
+            text,
+            source: SuggestionSource::Query,
+            count,
+        })
+        .collect();
+    // A trailing space means the last word is complete.
+    if !text.ends_with(char::is_whitespace) {
+        let (head, last_word) = match normalized.rsplit_once(' ') {
+            Some((head, last_word)) => (format!("{head} "), last_word),
+            None => (String::new(), normalized.as_str()),
+        };
+        let searcher = app_state.reader().searcher();
+        for (term, count) in complete_term(&searcher, last_word, limit)? {
+            let text = format!("{head}{term}");
+            if suggestions.iter().all(|suggestion| suggestion.text != text) {
+                suggestions.push(Suggestion {
+                    text,
+                    source: SuggestionSource::Title,
+                    count,
+                });
+            }
+        }
+    }
+    suggestions.truncate(limit);
+    Ok(suggestions)
+}
+
+#[derive(Deserialize)]
+pub struct SuggestParams {
+    /// The text typed so far.
+    q: String,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct SuggestResponse {
+    query: String,
+    suggestions: Vec<Suggestion>,
+}
+
+pub async fn suggest_handler(
+    State(app_state): State<AppState>,
+    Query(params): Query<SuggestParams>,
+) -> Result<Json<SuggestResponse>, Error> {
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_SUGGESTIONS)
+        .clamp(1, MAX_SUGGESTIONS);
+    let suggestions = suggest(&app_state, &params.q, limit)?;
+    Ok(Json(SuggestResponse {
+        query: params.q,
+        suggestions,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::suggest::{suggest, QueryLog, SuggestionSource};
+    use crate::{test_article, test_state};
+
+    #[test]
+    fn test_query_log_completes_popular_queries() {
+        let log = QueryLog::default();
+        for query in ["Hà Nội", "hà  nội", "hà giang", "bóng đá"] {
+            log.record(query);
+        }
+        assert_eq!(
+            log.complete("ha", 10),
+            [("hà nội".to_string(), 2), ("hà giang".to_string(), 1)]
+        );
+        assert!(log.complete("x", 10).is_empty());
+    }
+
+    #[test]
+    fn test_suggest() {
+        let state = test_state(&[
+            test_article("1", "Bão số 9 đổ bộ", 1),
+            test_article("2", "Bão lớn", 1),
+            test_article("3", "Báo chí", 1),
+        ]);
+        state.query_log.record("bão yagi");
+
+        let suggestions = suggest(&state, "Bã", 5).unwrap();
+        let texts: Vec<_> = suggestions.iter().map(|s| s.text.as_str()).collect();
+        assert_eq!(texts, ["bão yagi", "bão"]);
+        assert_eq!(suggestions[0].source, SuggestionSource::Query);
+        assert_eq!(suggestions[1].count, 2);
+
+        let suggestions = suggest(&state, "bão số ", 5).unwrap();
+        assert!(suggestions.is_empty());
+        let suggestions = suggest(&state, "tin ba", 5).unwrap();
+        let texts: Vec<_> = suggestions.iter().map(|s| s.text.as_str()).collect();
+        assert_eq!(texts, ["tin bao"]);
+    }
+}
@@ -0,0 +1,292 @@
+//! Vietnamese text analysis.
+//!
+//! Most users type without tone marks, so every syllable carrying diacritics is
+//! indexed twice at the same position: as written and folded to ASCII
+//! ("nội" and "noi", "đà" and "da"). An unaccented query then matches both
+//! spellings while an accented one still only matches the accented syllable.
+//!
+//! Vietnamese words are often made of several syllables ("Hà Nội", "bóng đá"),
+//! `SyllableBigramFilter` optionally indexes adjacent syllables as one more
+//! token ("hà_nội") so compound words can be matched as a unit.
+//!
+//! # Example
+//! ```rust
+//! use search_engine::vietnamese::vietnamese_analyzer;
+//! let mut analyzer = vietnamese_analyzer(false);
+//! let mut stream = analyzer.token_stream("Hà Nội");
+//! let mut tokens = vec![];
+//! while let Some(token) = stream.next() {
+//!     tokens.push(token.text.clone());
+//! }
+//! assert_eq!(tokens, ["hà", "ha", "nội", "noi"]);
+//! ```
+use crate::alpha_only_filter::CharacterClassFilter;
+use tantivy::tokenizer::{
+    LowerCaser, RemoveLongFilter, SimpleTokenizer, TextAnalyzer, Token, TokenFilter, TokenStream,
+    Tokenizer,
+};
+
+/// Removes the tone marks and vowel diacritics of a lowercase Vietnamese character.
+pub fn fold_char(c: char) -> char {
+    match c {
+        'á' | 'à' | 'ả' | 'ã' | 'ạ' | 'ă' | 'ắ' | 'ằ' | 'ẳ' | 'ẵ' | 'ặ' | 'â' | 'ấ' | 'ầ' | 'ẩ'
+        | 'ẫ' | 'ậ' => 'a',
+        'é' | 'è' | 'ẻ' | 'ẽ' | 'ẹ' | 'ê' | 'ế' | 'ề' | 'ể' | 'ễ' | 'ệ' => 'e',
+        'í' | 'ì' | 'ỉ' | 'ĩ' | 'ị' => 'i',
+        'ó' | 'ò' | 'ỏ' | 'õ' | 'ọ' | 'ô' | 'ố' | 'ồ' | 'ổ' | 'ỗ' | 'ộ' | 'ơ' | 'ớ' | 'ờ' | 'ở'
+        | 'ỡ' | 'ợ' => 'o',
+        'ú' | 'ù' | 'ủ' | 'ũ' | 'ụ' | 'ư' | 'ứ' | 'ừ' | 'ử' | 'ữ' | 'ự' => 'u',
+        'ý' | 'ỳ' | 'ỷ' | 'ỹ' | 'ỵ' => 'y',
+        'đ' => 'd',
+        _ => c,
+    }
+}
+
+/// Folds lowercase Vietnamese text to ASCII, e.g. "đường" to "duong".
+pub fn fold(text: &str) -> String {
+    text.chars().map(fold_char).collect()
+}
+
+/// The analyzer registered as "custom", `bigrams` enables `SyllableBigramFilter`.
+pub fn vietnamese_analyzer(bigrams: bool) -> TextAnalyzer {
+    let builder = TextAnalyzer::builder(SimpleTokenizer::default())
+        .filter(RemoveLongFilter::limit(10))
+        .filter(LowerCaser)
+        .filter(CharacterClassFilter::ALPHANUMERIC)
+        .dynamic();
+    let builder = if bigrams {
+        builder.filter_dynamic(SyllableBigramFilter)
+    } else {
+        builder
+    };
+    builder.filter_dynamic(VietnameseFoldingFilter).build()
+}
+
+/// `TokenFilter` emitting the ASCII-folded form of a token right after it,
+/// at the same position, whenever the two differ.
+#[derive(Clone)]
+pub struct VietnameseFoldingFilter;
+
+impl TokenFilter for VietnameseFoldingFilter {
+    type Tokenizer<T: Tokenizer> = VietnameseFoldingFilterWrapper<T>;
+
+    fn transform<T: Tokenizer>(self, tokenizer: T) -> VietnameseFoldingFilterWrapper<T> {
+        VietnameseFoldingFilterWrapper(tokenizer)
+    }
+}
+
+#[derive(Clone)]
+pub struct VietnameseFoldingFilterWrapper<T>(T);
+
+impl<T: Tokenizer> Tokenizer for VietnameseFoldingFilterWrapper<T> {
+    type TokenStream<'a> = VietnameseFoldingFilterStream<T::TokenStream<'a>>;
+
+    fn token_stream<'a>(&'a mut self, text: &'a str) -> Self::TokenStream<'a> {
+        VietnameseFoldingFilterStream {
+            tail: self.0.token_stream(text),
+            folded: Token::default(),
+            pending: false,
+            emitting_folded: false,
+        }
+    }
+}
+
+pub struct VietnameseFoldingFilterStream<T> {
+    tail: T,
+    folded: Token,
+    /// The folded form of the current tail token still has to be emitted.
+    pending: bool,
+    emitting_folded: bool,
+}
+
+impl<T: TokenStream> TokenStream for VietnameseFoldingFilterStream<T> {
+    fn advance(&mut self) -> bool {
+        if self.pending {
+            self.pending = false;
+            self.emitting_folded = true;
+            return true;
+        }
+        self.emitting_folded = false;
+        if !self.tail.advance() {
+            return false;
+        }
+        let token = self.tail.token();
+        let folded = fold(&token.text);
+        if folded != token.text {
+            self.folded.clone_from(token);
+            self.folded.text = folded;
+            self.pending = true;
+        }
+        true
+    }
+
+    fn token(&self) -> &Token {
+        if self.emitting_folded {
+            &self.folded
+        } else {
+            self.tail.token()
+        }
+    }
+
+    fn token_mut(&mut self) -> &mut Token {
+        if self.emitting_folded {
+            &mut self.folded
+        } else {
+            self.tail.token_mut()
+        }
+    }
+}
+
+/// `TokenFilter` emitting, before every syllable directly following another one,
+/// a token joining both with an underscore at the position of the first.
+///
+/// Syllables separated by anything else than a single character, typically
+/// punctuation and a space, are not joined.
+#[derive(Clone)]
+pub struct SyllableBigramFilter;
+
+impl TokenFilter for SyllableBigramFilter {
+    type Tokenizer<T: Tokenizer> = SyllableBigramFilterWrapper<T>;
+
+    fn transform<T: Tokenizer>(self, tokenizer: T) -> SyllableBigramFilterWrapper<T> {
+        SyllableBigramFilterWrapper(tokenizer)
+    }
+}
+
+#[derive(Clone)]
+pub struct SyllableBigramFilterWrapper<T>(T);
+
+impl<T: Tokenizer> Tokenizer for SyllableBigramFilterWrapper<T> {
+    type TokenStream<'a> = SyllableBigramFilterStream<T::TokenStream<'a>>;
+
+    fn token_stream<'a>(&'a mut self, text: &'a str) -> Self::TokenStream<'a> {
+        SyllableBigramFilterStream {
+            tail: self.0.token_stream(text),
+            previous: None,
+            bigram: Token::default(),
+            pending: false,
+            emitting_bigram: false,
+        }
+    }
+}
+
+pub struct SyllableBigramFilterStream<T> {
+    tail: T,
+    previous: Option<Token>,
+    bigram: Token,
+    /// The current tail token still has to be emitted after the bigram.
+    pending: bool,
+    emitting_bigram: bool,
+}
+
+impl<T: TokenStream> TokenStream for SyllableBigramFilterStream<T> {
+    fn advance(&mut self) -> bool {
+        self.emitting_bigram = false;
+        if self.pending {
+            self.pending = false;
+            return true;
+        }
+        if !self.tail.advance() {
+            return false;
+        }
+        let token = self.tail.token();
+        if let Some(previous) = &self.previous {
+            if token.position == previous.position + 1
+                && token.offset_from <= previous.offset_to + 1
+            {
+                self.bigram = Token {
+                    offset_from: previous.offset_from,
+                    offset_to: token.offset_to,
+                    position: previous.position,
+                    text: format!("{}_{}", previous.text, token.text),
+                    position_length: 2,
+                };
+                self.emitting_bigram = true;
+                self.pending = true;
+            }
+        }
+        self.previous = Some(token.clone());
+        true
+    }
+
+    fn token(&self) -> &Token {
+        if self.emitting_bigram {
+            &self.bigram
+        } else {
+            self.tail.token()
+        }
+    }
+
+    fn token_mut(&mut self) -> &mut Token {
+        if self.emitting_bigram {
+            &mut self.bigram
+        } else {
+            self.tail.token_mut()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assert_token;
+    use crate::vietnamese::{fold, vietnamese_analyzer};
+    use crate::wrapper::{query_wrapper, SearchOptions};
+    use crate::{test_article, test_state, AppState};
+    use tantivy::tokenizer::{TextAnalyzer, Token};
+
+    #[test]
+    fn test_fold() {
+        assert_eq!(fold("đường phố hà nội"), "duong pho ha noi");
+        assert_eq!(fold("nghiêng ngả"), "nghieng nga");
+    }
+
+    #[test]
+    fn test_folded_tokens_share_position() {
+        let tokens = token_stream_helper(vietnamese_analyzer(false), "Đà Nẵng, 2023");
+        assert_eq!(tokens.len(), 5);
+        assert_token(&tokens[0], 0, "đà", 0, 4);
+        assert_token(&tokens[1], 0, "da", 0, 4);
+        assert_token(&tokens[2], 1, "nẵng", 5, 11);
+        assert_token(&tokens[3], 1, "nang", 5, 11);
+        assert_token(&tokens[4], 2, "2023", 13, 17);
+    }
+
+    #[test]
+    fn test_syllable_bigrams() {
+        let tokens = token_stream_helper(vietnamese_analyzer(true), "bóng đá. Hà");
+        let texts: Vec<_> = tokens.iter().map(|token| token.text.as_str()).collect();
+        assert_eq!(
+            texts,
+            ["bóng", "bong", "bóng_đá", "bong_da", "đá", "da", "hà", "ha"]
+        );
+        assert_token(&tokens[2], 0, "bóng_đá", 0, 10);
+    }
+
+    #[test]
+    fn test_unaccented_query_matches_accented_article() {
+        let state = test_state(&[test_article("1", "Người Hà Nội", 1)]);
+        let count = |state: &AppState, query: &str| {
+            let results = query_wrapper(state, query, &SearchOptions::default()).unwrap();
+            // Only count exact matches.
+            if results.fuzzy {
+                0
+            } else {
+                results.count
+            }
+        };
+        assert_eq!(count(&state, "ha noi"), 1);
+        assert_eq!(count(&state, "\"nguoi ha noi\""), 1);
+        assert_eq!(count(&state, "Hà Nội"), 1);
+        assert_eq!(count(&state, "hả"), 0);
+    }
+
+    fn token_stream_helper(mut analyzer: TextAnalyzer, text: &str) -> Vec<Token> {
+        let mut token_stream = analyzer.token_stream(text);
+        let mut tokens: Vec<Token> = vec![];
+        let mut add_token = |token: &Token| {
+            tokens.push(token.clone());
+        };
+        token_stream.process(&mut add_token);
+        tokens
+    }
+}
@@ -12,62 +12,690 @@
 
 // ---
 // Importing tantivy...
-use tantivy::collector::TopDocs;
-use tantivy::collector::{Count, MultiCollector};
-use tantivy::query::QueryParser;
-use tantivy::schema::*;
-use tantivy::{Index, ReloadPolicy};
+use serde::Deserialize;
+use sqlx::types::chrono::{DateTime, Utc};
+use std::ops::Bound;
+use tantivy::collector::{Count, TopDocs};
+use tantivy::query::{BooleanQuery, ConstScoreQuery, Occur, Query, RangeQuery};
+use tantivy::query_grammar::{Delimiter, UserInputAst, UserInputBound, UserInputLeaf};
+use tantivy::{DocAddress, Order, Score};
+
+use crate::article::{to_tantivy_date, SearchHit};
+use crate::config::Boosts;
+use crate::error::Error;
+use crate::facet::{facet_collectors, FacetFilters, Facets};
+use crate::fuzzy::{excluded_clauses, with_fuzzy};
+use crate::pagination::Page;
+use crate::snippet::{Highlighter, SnippetOptions};
+use crate::spelling::SUGGEST_BELOW;
+use crate::AppState;
+
+/// Order of the hits of a search.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Sort {
+    #[default]
+    Relevance,
+    Newest,
+    Oldest,
+}
+
+/// A field searched when the query does not name one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchField {
+    Title,
+    Summary,
+    Content,
+}
+
+impl SearchField {
+    pub const ALL: [SearchField; 3] = [
+        SearchField::Title,
+        SearchField::Summary,
+        SearchField::Content,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SearchField::Title => "title",
+            SearchField::Summary => "summary",
+            SearchField::Content => "content",
+        }
+    }
+
+    pub fn boost(self, boosts: &Boosts) -> Score {
+        match self {
+            SearchField::Title => boosts.title,
+            SearchField::Summary => boosts.summary,
+            SearchField::Content => boosts.content,
+        }
+    }
+}
+
+/// How the query text is interpreted.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueryMode {
+    /// The words of the query are searched, any other character is ignored.
+    Simple,
+    /// Tantivy's query syntax: `title:`, `+`/`-`, `AND`/`OR`/`NOT`, "phrases"...
+    #[default]
+    Advanced,
+    /// Like `Advanced`, but a malformed query is rejected instead of recovered.
+    Strict,
+}
+
+/// Reduces `text` to its words, each quoted so none of them has a meaning for the
+/// query parser, e.g. `title:"Hà Nội` becomes `"title" "Hà" "Nội"`.
+pub fn escape_query(text: &str) -> String {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| format!("\"{word}\""))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Writes `ast` back in the query syntax, so that parsing the result gives `ast` again.
+pub fn render_query(ast: &UserInputAst) -> String {
+    match ast {
+        UserInputAst::Clause(clauses) => clauses
+            .iter()
+            .filter(|(_, clause)| !matches!(clause, UserInputAst::Clause(clauses) if clauses.is_empty()))
+            .map(|(occur, clause)| {
+                let prefix = match occur {
+                    Some(Occur::Must) => "+",
+                    Some(Occur::MustNot) => "-",
+                    Some(Occur::Should) | None => "",
+                };
+                match clause {
+                    UserInputAst::Clause(_) => format!("{prefix}({})", render_query(clause)),
+                    _ => format!("{prefix}{}", render_query(clause)),
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" "),
+        UserInputAst::Leaf(leaf) => render_leaf(leaf),
+        UserInputAst::Boost(ast, boost) => match **ast {
+            UserInputAst::Leaf(_) => format!("{}^{boost}", render_query(ast)),
+            _ => format!("({})^{boost}", render_query(ast)),
+        },
+    }
+}
+
+fn render_leaf(leaf: &UserInputLeaf) -> String {
+    let quote = |text: &str| format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""));
+    let field = |field: &Option<String>| {
+        field
+            .as_ref()
+            .map(|field| format!("{field}:"))
+            .unwrap_or_default()
+    };
+    match leaf {
+        UserInputLeaf::Literal(literal) => {
+            // A word left as is by the lenient parser may be an operator, e.g. the
+            // `AND` ending "mùa AND".
+            let bare = literal.delimiter == Delimiter::None
+                && literal.phrase.chars().all(char::is_alphanumeric)
+                && !["AND", "OR", "NOT", "IN"].contains(&literal.phrase.as_str());
+            let phrase = if bare {
+                literal.phrase.clone()
+            } else {
+                quote(&literal.phrase)
+            };
+            let suffix = if literal.slop > 0 {
+                format!("~{}", literal.slop)
+            } else if literal.prefix {
+                "*".to_string()
+            } else {
+                String::new()
+            };
+            format!("{}{phrase}{suffix}", field(&literal.field_name))
+        }
+        UserInputLeaf::All => "*".to_string(),
+        UserInputLeaf::Range {
+            field: name,
+            lower,
+            upper,
+        } => {
+            // The parser keeps bounds verbatim, quotes included.
+            let lower = match lower {
+                UserInputBound::Inclusive(word) => format!("[{word}"),
+                UserInputBound::Exclusive(word) => format!("{{{word}"),
+                UserInputBound::Unbounded => "{*".to_string(),
+            };
+            let upper = match upper {
+                UserInputBound::Inclusive(word) => format!("{word}]"),
+                UserInputBound::Exclusive(word) => format!("{word}}}"),
+                UserInputBound::Unbounded => "*}".to_string(),
+            };
+            format!("{}{lower} TO {upper}", field(name))
+        }
+        UserInputLeaf::Set {
+            field: name,
+            elements,
+        } => {
+            let elements: Vec<String> = elements.iter().map(|element| quote(element)).collect();
+            format!("{} IN [{}]", field(name), elements.join(" "))
+        }
+    }
+}
+
+/// Everything about a search besides the query itself.
+#[derive(Debug, Clone, Default)]
+pub struct SearchOptions {
+    pub page: Page,
+    pub snippets: SnippetOptions,
+    /// Inclusive lower bound on `created_time`.
+    pub from: Option<DateTime<Utc>>,
+    /// Inclusive upper bound on `created_time`.
+    pub to: Option<DateTime<Utc>>,
+    pub sort: Sort,
+    pub filters: FacetFilters,
+    /// Count the facets of the matching articles.
+    pub facets: bool,
+    /// Fields searched by terms without a field name, all of them when empty.
+    pub fields: Vec<SearchField>,
+    pub mode: QueryMode,
+    /// Also match terms within a few typos of the query's.
+    pub fuzzy: bool,
+}
+
+/// The requested page of hits and how the query was understood.
+#[derive(Debug, Default)]
+pub struct SearchResults {
+    /// Number of articles matching the query, across all pages.
+    pub count: usize,
+    pub hits: Vec<SearchHit>,
+    /// Problems found in the query, which was then searched as best as possible.
+    pub warnings: Vec<String>,
+    /// The query actually searched when it differs from the one given.
+    pub recovered_query: Option<String>,
+    /// Whether terms within a few typos of the query's were matched.
+    pub fuzzy: bool,
+    /// The query with its misspelled words corrected, when it has few exact hits.
+    pub suggestion: Option<String>,
+    /// Set when asked for.
+    pub facets: Option<Facets>,
+}
 
 pub fn query_wrapper(
-    index: Index,
-    query: String,
-    schema: Schema,
-) -> tantivy::Result<(usize, Vec<String>)> {
-    let title_field = schema.get_field("title").unwrap();
-    let content_field = schema.get_field("content").unwrap();
-    let summary_field = schema.get_field("summary").unwrap();
-    // let url_field = schema.get_field("url").unwrap();
-    // let timestamp_field = schema.get_field("created_time").unwrap();
-    // let id_field = schema.get_field("id").unwrap();
-
-    let reader = index
-        .reader_builder()
-        .reload_policy(ReloadPolicy::OnCommit)
-        .try_into()?;
-
-    let searcher = reader.searcher();
+    app_state: &AppState,
+    query: &str,
+    options: &SearchOptions,
+) -> Result<SearchResults, Error> {
+    let mut results = parse_and_search(app_state, query, options)?;
+    if results.count < SUGGEST_BELOW || results.fuzzy {
+        let vocabulary = app_state.vocabulary.get(&app_state.reader().searcher())?;
+        results.suggestion = vocabulary.suggest(query, &app_state.fuzzy);
+    }
+    Ok(results)
+}
 
+fn parse_and_search(
+    app_state: &AppState,
+    query: &str,
+    options: &SearchOptions,
+) -> Result<SearchResults, Error> {
     // ### Query
 
     // The query parser can interpret human queries.
     // Here, if the user does not specify which
     // field they want to search, tantivy will search
-    // in both title and content.
-    let query_parser =
-        QueryParser::for_index(&index, vec![title_field, summary_field, content_field]);
-    // `QueryParser` may fail if the query is not in the right
-    // format. For user facing applications, this can be a problem.
-    // A ticket has been opened regarding this problem.
-    let query = query_parser.parse_query(&query)?;
-
-    // A query defines a set of documents, as
-    // well as the way they should be scored.
-    //
-
-    // We can now perform our query.
-    let mut collectors = MultiCollector::new();
-    let top_docs_handle = collectors.add_collector(TopDocs::with_limit(25000));
-    let count_handle = collectors.add_collector(Count);
-    let mut multi_fruit = searcher.search(&query, &collectors)?;
-    let mut result: Vec<String> = Vec::new();
-
-    let top_docs = top_docs_handle.extract(&mut multi_fruit);
-    let count = count_handle.extract(&mut multi_fruit);
-
-    println!("Total hits: {}", count);
-    for (_score, doc_address) in top_docs {
+    // in title, summary and content.
+    let query_parser = app_state.query_parser(&options.fields)?;
+    let bag_of_words = escape_query(query);
+    if options.mode == QueryMode::Simple {
+        let (parsed, _) = query_parser.parse_query_lenient(&bag_of_words);
+        return search_with_fallbacks(app_state, parsed, &[], options);
+    }
+    let (ast, _) = tantivy::query_grammar::parse_query_lenient(query);
+    let excluded: Vec<Box<dyn Query>> = excluded_clauses(&ast)
+        .into_iter()
+        .filter_map(|clause| query_parser.build_query_from_user_input_ast(clause).ok())
+        .collect();
+    // Users make typos, a query that is not in the right format is still searched.
+    let error = match query_parser.parse_query(query) {
+        Ok(parsed) => return search_with_fallbacks(app_state, parsed, &excluded, options),
+        Err(error) => error,
+    };
+    // Nothing is left to search without the syntax, e.g. for "+(-".
+    if options.mode == QueryMode::Strict || bag_of_words.is_empty() {
+        return Err(Error::BadQuery(error));
+    }
+    let (parsed, mut errors) = query_parser.parse_query_lenient(query);
+    if errors.is_empty() {
+        errors.push(error);
+    }
+    let mut warnings: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+    warnings.dedup();
+    let mut results = search_wrapper(app_state, parsed.box_clone(), options)?;
+    results.recovered_query = Some(render_query(&ast));
+    // Whatever was left of the query matches nothing, search its words instead.
+    if results.count == 0 && !bag_of_words.is_empty() {
+        let (parsed, _) = query_parser.parse_query_lenient(&bag_of_words);
+        results = search_with_fallbacks(app_state, parsed, &[], options)?;
+        results.recovered_query = Some(bag_of_words);
+    } else if results.count == 0 {
+        results = search_with_fallbacks(app_state, parsed, &excluded, options)?;
+    }
+    results.warnings = warnings;
+    Ok(results)
+}
+
+/// Searches `query` with typo tolerance when asked to, or when it has no exact hits.
+/// `excluded` are the clauses of the query that must not match.
+fn search_with_fallbacks(
+    app_state: &AppState,
+    query: Box<dyn Query>,
+    excluded: &[Box<dyn Query>],
+    options: &SearchOptions,
+) -> Result<SearchResults, Error> {
+    let schema = app_state.index().schema();
+    let fuzzy = |query| {
+        with_fuzzy(
+            query,
+            excluded,
+            &schema,
+            &app_state.boosts,
+            &app_state.fuzzy,
+        )
+    };
+    if options.fuzzy {
+        let mut results = search_wrapper(app_state, fuzzy(query), options)?;
+        results.fuzzy = true;
+        return Ok(results);
+    }
+    let results = search_wrapper(app_state, query.box_clone(), options)?;
+    if results.count > 0 || !app_state.fuzzy.fallback {
+        return Ok(results);
+    }
+    let mut results = search_wrapper(app_state, fuzzy(query), options)?;
+    results.fuzzy = true;
+    Ok(results)
+}
+
+/// Runs an already built query, `AllQuery` lists every article.
+pub fn search_wrapper(
+    app_state: &AppState,
+    mut query: Box<dyn Query>,
+    options: &SearchOptions,
+) -> Result<SearchResults, Error> {
+    let schema = app_state.index().schema();
+    // The reader is shared, acquiring a searcher is cheap.
+    let searcher = app_state.reader().searcher();
+
+    // Filters don't change the score of the hits.
+    let mut filters: Vec<Box<dyn Query>> = Vec::new();
+    if options.from.is_some() || options.to.is_some() {
+        let bound = |date: Option<DateTime<Utc>>| match date {
+            Some(date) => Bound::Included(to_tantivy_date(&date)),
+            None => Bound::Unbounded,
+        };
+        filters.push(Box::new(RangeQuery::new_date_bounds(
+            "created_time".to_string(),
+            bound(options.from),
+            bound(options.to),
+        )));
+    }
+    for filter in options.filters.queries(&schema)? {
+        filters.push(Box::new(filter));
+    }
+    if !filters.is_empty() {
+        let mut clauses = vec![(Occur::Must, query)];
+        for filter in filters {
+            let filter: Box<dyn Query> = Box::new(ConstScoreQuery::new(filter, 0.0));
+            clauses.push((Occur::Must, filter));
+        }
+        query = Box::new(BooleanQuery::new(clauses));
+    }
+
+    // We can now perform our query, only collecting the requested page.
+    let page = options.page;
+    let top_docs = TopDocs::with_limit(page.limit).and_offset(page.offset);
+    let facets = options.facets.then(|| facet_collectors(&options.filters));
+    let (top_docs, count, facets): (Vec<(Option<Score>, DocAddress)>, usize, _) = match options.sort
+    {
+        Sort::Relevance => {
+            let (top_docs, count, facets) = searcher.search(&query, &(top_docs, Count, facets))?;
+            let top_docs = top_docs.into_iter().map(|(score, doc)| (Some(score), doc));
+            (top_docs.collect(), count, facets)
+        }
+        Sort::Newest | Sort::Oldest => {
+            let order = if options.sort == Sort::Newest {
+                Order::Desc
+            } else {
+                Order::Asc
+            };
+            let top_docs = top_docs.order_by_fast_field::<tantivy::DateTime>("created_time", order);
+            let (top_docs, count, facets) = searcher.search(&query, &(top_docs, Count, facets))?;
+            let top_docs = top_docs.into_iter().map(|(_date, doc)| (None, doc));
+            (top_docs.collect(), count, facets)
+        }
+    };
+
+    tracing::debug!("total hits: {}", count);
+    let mut result: Vec<SearchHit> = Vec::new();
+    let highlighter = Highlighter::new(&searcher, &query, &schema, &options.snippets)?;
+    for (score, doc_address) in top_docs {
         let retrieved_doc = searcher.doc(doc_address)?;
-        result.push(schema.to_json(&retrieved_doc));
+        let mut hit = SearchHit::from_doc(&schema, &retrieved_doc, score);
+        hit.snippets = highlighter.snippets(&retrieved_doc);
+        result.push(hit);
+    }
+    Ok(SearchResults {
+        count,
+        hits: result,
+        facets: facets.map(|facets| Facets::new(facets, &options.filters)),
+        ..SearchResults::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::facet::{FacetCount, FacetFilters};
+    use crate::wrapper::{
+        escape_query, query_wrapper, render_query, search_wrapper, QueryMode, SearchField,
+        SearchOptions, Sort,
+    };
+    use crate::{test_article, test_state};
+    use sqlx::types::chrono::{TimeZone, Utc};
+    use std::sync::Arc;
+    use tantivy::query::AllQuery;
+    use tantivy::query_grammar::parse_query_lenient;
+
+    fn ids(state: &crate::AppState, query: &str, options: &SearchOptions) -> Vec<String> {
+        let hits = query_wrapper(state, query, options).unwrap().hits;
+        hits.into_iter().map(|hit| hit.id).collect()
+    }
+
+    #[test]
+    fn test_query_returns_typed_hits() {
+        let state = test_state(&[test_article("1", "Đội tuyển Việt Nam", 1)]);
+        let results = query_wrapper(&state, "tuyển", &SearchOptions::default()).unwrap();
+        assert_eq!(results.count, 1);
+        assert!(results.warnings.is_empty());
+        assert_eq!(results.recovered_query, None);
+        assert_eq!(results.suggestion, None);
+        let hits = results.hits;
+        assert_eq!(hits[0].id, "1");
+        assert_eq!(hits[0].title, "Đội tuyển Việt Nam");
+        assert_eq!(hits[0].url, "/the-thao/1.htm");
+        assert_eq!(
+            hits[0].created_time.unwrap().to_rfc3339(),
+            "2023-11-01T10:00:00+00:00"
+        );
+        assert!(hits[0].score.unwrap() > 0.0);
+        assert_eq!(
+            hits[0].snippets.title.as_deref(),
+            Some("Đội <b>tuyển</b> Việt Nam")
+        );
+    }
+
+    #[test]
+    fn test_malformed_query_is_recovered() {
+        let state = test_state(&[test_article("1", "Hà Nội mùa thu", 1)]);
+        let results = query_wrapper(&state, "title:\"Hà Nội", &SearchOptions::default()).unwrap();
+        assert_eq!(results.count, 1);
+        assert!(!results.warnings.is_empty());
+        assert_eq!(results.recovered_query.as_deref(), Some("title:\"Hà Nội\""));
+
+        let results = query_wrapper(&state, "mùa AND", &SearchOptions::default()).unwrap();
+        assert_eq!(results.count, 1);
+        assert_eq!(results.recovered_query.as_deref(), Some("mùa \"AND\""));
+
+        // The recovered query matches nothing, its words are searched instead.
+        let results = query_wrapper(&state, "nothing:thu", &SearchOptions::default()).unwrap();
+        assert_eq!(results.count, 1);
+        assert_eq!(results.warnings.len(), 1);
+        assert_eq!(
+            results.recovered_query.as_deref(),
+            Some("\"nothing\" \"thu\"")
+        );
+    }
+
+    #[test]
+    fn test_rendered_query_parses_back() {
+        for (query, rendered) in [
+            ("+a -b (c OR d)", "+a -b (c d)"),
+            (
+                "title:\"x y\"~2 pre* a^2 (a b)^3",
+                "title:\"x y\"~2 pre* a^2 (a b)^3",
+            ),
+            ("id: IN [1 2] -(a +b", "id: IN [\"1\" \"2\"] -(a +b)"),
+            ("created_time:[2020 TO *}", "created_time:[2020 TO *}"),
+        ] {
+            let (ast, _) = parse_query_lenient(query);
+            assert_eq!(render_query(&ast), rendered);
+            let (again, errors) = parse_query_lenient(rendered);
+            assert!(errors.is_empty(), "{rendered}: {errors:?}");
+            assert_eq!(render_query(&again), rendered);
+        }
+    }
+
+    #[test]
+    fn test_unrecoverable_query_is_bad_query() {
+        let state = test_state(&[test_article("1", "Hà Nội mùa thu", 1)]);
+        let result = query_wrapper(&state, "+(-", &SearchOptions::default());
+        assert!(matches!(result, Err(Error::BadQuery(_))));
+        let strict = SearchOptions {
+            mode: QueryMode::Strict,
+            ..SearchOptions::default()
+        };
+        let result = query_wrapper(&state, "title:\"Hà Nội", &strict);
+        assert!(matches!(result, Err(Error::BadQuery(_))));
+        assert_eq!(ids(&state, "title:\"Hà Nội\"", &strict), ["1"]);
+    }
+
+    #[test]
+    fn test_reload_makes_commit_visible() {
+        let state = test_state(&[test_article("1", "Đội tuyển Việt Nam", 1)]);
+        let mut writer = state.index().writer(15_000_000).unwrap();
+        let schema = state.index().schema();
+        writer
+            .add_document(test_article("2", "Tuyển thủ", 2).to_document(&schema))
+            .unwrap();
+        writer.commit().unwrap();
+        state.reload().unwrap();
+
+        let results = query_wrapper(&state, "tuyển", &SearchOptions::default()).unwrap();
+        assert_eq!(results.count, 2);
+    }
+
+    #[test]
+    fn test_date_range_and_sort() {
+        let state = test_state(&[
+            test_article("1", "tuyển", 1),
+            test_article("2", "tuyển", 2),
+            test_article("3", "tuyển", 3),
+        ]);
+        let mut options = SearchOptions {
+            sort: Sort::Newest,
+            ..SearchOptions::default()
+        };
+        assert_eq!(ids(&state, "tuyển", &options), ["3", "2", "1"]);
+
+        options.sort = Sort::Oldest;
+        options.from = Some(Utc.with_ymd_and_hms(2023, 11, 2, 0, 0, 0).unwrap());
+        assert_eq!(ids(&state, "tuyển", &options), ["2", "3"]);
+
+        options.to = Some(Utc.with_ymd_and_hms(2023, 11, 2, 10, 0, 0).unwrap());
+        assert_eq!(ids(&state, "tuyển", &options), ["2"]);
+    }
+
+    #[test]
+    fn test_latest_filtered_by_source_and_category() {
+        let mut other = test_article("3", "tuyển", 3);
+        other.url = "https://www.vnexpress.net/the-thao/3.html".to_string();
+        let mut news = test_article("4", "tuyển", 4);
+        news.url = "/xa-hoi/4.htm".to_string();
+        let state = test_state(&[
+            test_article("1", "tuyển", 1),
+            test_article("2", "tuyển", 2),
+            other,
+            news,
+        ]);
+        let latest = |source: Option<&str>, category: Option<&str>| {
+            let options = SearchOptions {
+                sort: Sort::Newest,
+                filters: FacetFilters {
+                    source: source.map(str::to_string),
+                    category: category.map(str::to_string),
+                    month: None,
+                },
+                ..SearchOptions::default()
+            };
+            let hits = search_wrapper(&state, Box::new(AllQuery), &options)
+                .unwrap()
+                .hits;
+            hits.into_iter().map(|hit| hit.id).collect::<Vec<_>>()
+        };
+        assert_eq!(latest(None, None), ["4", "3", "2", "1"]);
+        assert_eq!(latest(None, Some("the-thao")), ["3", "2", "1"]);
+        assert_eq!(latest(Some("dantri.com.vn"), Some("the-thao")), ["2", "1"]);
+        assert_eq!(latest(Some("vnexpress.net"), None), ["3"]);
+    }
+
+    #[test]
+    fn test_facet_counts_and_filters() {
+        let mut football = test_article("3", "tuyển", 3);
+        football.url = "/the-thao/bong-da/3.htm".to_string();
+        let mut other = test_article("4", "tuyển", 4);
+        other.url = "https://vnexpress.net/kinh-doanh/4.html".to_string();
+        other.timestamp = Utc.with_ymd_and_hms(2023, 12, 1, 10, 0, 0).unwrap();
+        let state = test_state(&[
+            test_article("1", "tuyển", 1),
+            test_article("2", "tuyển", 2),
+            football,
+            other,
+        ]);
+        let search = |filters: FacetFilters| {
+            let options = SearchOptions {
+                filters,
+                facets: true,
+                ..SearchOptions::default()
+            };
+            query_wrapper(&state, "tuyển", &options).unwrap()
+        };
+        let counts = |counts: &[FacetCount]| {
+            counts
+                .iter()
+                .map(|count| (count.value.clone(), count.count))
+                .collect::<Vec<_>>()
+        };
+
+        let results = search(FacetFilters::default());
+        let facets = results.facets.unwrap();
+        assert_eq!(
+            counts(&facets.source),
+            [
+                ("dantri.com.vn".to_string(), 3),
+                ("vnexpress.net".to_string(), 1)
+            ]
+        );
+        assert_eq!(
+            counts(&facets.category),
+            [("/the-thao".to_string(), 3), ("/kinh-doanh".to_string(), 1)]
+        );
+        assert_eq!(
+            counts(&facets.month),
+            [("2023-12".to_string(), 1), ("2023-11".to_string(), 3)]
+        );
+        assert_eq!(results.hits[0].category, "/the-thao");
+
+        // Narrowing to a category breaks it down into its subcategories.
+        let results = search(FacetFilters {
+            category: Some("/the-thao".to_string()),
+            ..FacetFilters::default()
+        });
+        assert_eq!(results.count, 3);
+        let facets = results.facets.unwrap();
+        assert_eq!(
+            counts(&facets.category),
+            [("/the-thao/bong-da".to_string(), 1)]
+        );
+
+        let results = search(FacetFilters {
+            category: Some("the-thao/bong-da".to_string()),
+            month: Some("2023-11".to_string()),
+            ..FacetFilters::default()
+        });
+        let ids: Vec<_> = results.hits.iter().map(|hit| hit.id.as_str()).collect();
+        assert_eq!(ids, ["3"]);
+        let results = search(FacetFilters {
+            month: Some("2023-12".to_string()),
+            ..FacetFilters::default()
+        });
+        assert_eq!(results.hits[0].source, "vnexpress.net");
+    }
+
+    #[test]
+    fn test_title_matches_outrank_content_matches() {
+        let mut in_content = test_article("1", "Thời sự", 1);
+        in_content.content = "bão số 9".to_string();
+        let in_title = test_article("2", "Bão số 9", 1);
+        let state = test_state(&[in_content, in_title]);
+        assert_eq!(ids(&state, "bão", &SearchOptions::default()), ["2", "1"]);
+
+        let options = SearchOptions {
+            fields: vec![SearchField::Content],
+            ..SearchOptions::default()
+        };
+        assert_eq!(ids(&state, "bão", &options), ["1"]);
+    }
+
+    #[test]
+    fn test_misspelled_query_gets_a_suggestion() {
+        let state = test_state(&[
+            test_article("1", "Hà Nội mùa thu", 1),
+            test_article("2", "Hà Nội mùa đông", 2),
+        ]);
+        let results = query_wrapper(&state, "title:hả nôi", &SearchOptions::default()).unwrap();
+        assert_eq!(results.suggestion.as_deref(), Some("title:hà nội"));
+
+        let results = query_wrapper(&state, "mùa thư", &SearchOptions::default()).unwrap();
+        assert_eq!(results.suggestion.as_deref(), Some("mùa thu"));
+        let results = query_wrapper(&state, "mùa thu", &SearchOptions::default()).unwrap();
+        assert_eq!(results.suggestion, None);
+    }
+
+    #[test]
+    fn test_vocabulary_is_read_once_per_generation() {
+        let state = test_state(&[test_article("1", "Hà Nội mùa thu", 1)]);
+        let vocabulary = state.vocabulary.get(&state.reader().searcher()).unwrap();
+        let again = state.vocabulary.get(&state.reader().searcher()).unwrap();
+        assert!(Arc::ptr_eq(&vocabulary, &again));
+
+        let mut writer = state.index().writer(15_000_000).unwrap();
+        let schema = state.index().schema();
+        writer
+            .add_document(test_article("2", "Thành phố mùa mưa", 2).to_document(&schema))
+            .unwrap();
+        writer.commit().unwrap();
+        state.reload().unwrap();
+        let results = query_wrapper(&state, "thành phớ", &SearchOptions::default()).unwrap();
+        assert_eq!(results.suggestion.as_deref(), Some("thành phố"));
+        let again = state.vocabulary.get(&state.reader().searcher()).unwrap();
+        assert!(!Arc::ptr_eq(&vocabulary, &again));
+    }
+
+    #[test]
+    fn test_simple_mode_escapes_syntax() {
+        assert_eq!(escape_query(r#"title:"Hà  Nội\"#), r#""title" "Hà" "Nội""#);
+
+        let state = test_state(&[test_article("1", "Hà Nội: phố cổ", 1)]);
+        let simple = SearchOptions {
+            mode: QueryMode::Simple,
+            ..SearchOptions::default()
+        };
+        for query in ["\"Hà Nội", "hà nội AND", "(phố -cổ", "phố title:"] {
+            assert_eq!(ids(&state, query, &simple), ["1"], "{query}");
+        }
+        let advanced = SearchOptions::default();
+        assert_eq!(ids(&state, "hà -cổ", &advanced), Vec::<String>::new());
+        assert_eq!(ids(&state, "hà NOT phố", &advanced), Vec::<String>::new());
+        let results = query_wrapper(&state, "\"Hà Nội", &advanced).unwrap();
+        assert_eq!(results.warnings.len(), 1);
     }
-    Ok((count, result))
 }
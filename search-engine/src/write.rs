@@ -0,0 +1,457 @@
+//! Creating, updating and deleting articles over HTTP.
+//!
+//! Writes need the `Authorization: Bearer <api_token>` header, they are forbidden when
+//! the server has no token configured. Articles are written to Postgres first when
+//! the server has a database, so the index can always be rebuilt from it, then to
+//! the index. A request only returns once the writer of the server committed its
+//! change and it is visible to searches.
+use crate::article::{is_indexed, Article};
+use crate::error::Error;
+use crate::writer::IndexOp;
+use crate::AppState;
+use axum::async_trait;
+use axum::extract::{FromRequestParts, Path, State};
+use axum::http::header::AUTHORIZATION;
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use sqlx::types::chrono::{DateTime, Utc};
+use tantivy::Opstamp;
+
+/// Largest body accepted by the bulk endpoint, in bytes.
+pub const BULK_BODY_LIMIT: usize = 64 * 1024 * 1024;
+
+/// Proof that the request carries the write API token.
+#[derive(Debug)]
+pub struct Authorized(());
+
+/// Accepts the `Bearer` scheme as well as `Token`, the one our 401 responses advertise.
+fn authorize(api_token: Option<&str>, headers: &HeaderMap) -> Result<Authorized, Error> {
+    let api_token = api_token.ok_or(Error::Forbidden)?;
+    let token = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| {
+            value
+                .strip_prefix("Bearer ")
+                .or_else(|| value.strip_prefix("Token "))
+        })
+        .ok_or(Error::Unauthorized)?;
+    // Compares every byte so the time taken does not tell how much of the token matched.
+    let matches = token.len() == api_token.len()
+        && token
+            .bytes()
+            .zip(api_token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0;
+    if !matches {
+        return Err(Error::Unauthorized);
+    }
+    Ok(Authorized(()))
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for Authorized {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Error> {
+        authorize(state.api_token.as_deref(), &parts.headers)
+    }
+}
+
+/// An article as sent by clients, the id is taken from the path when updating.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ArticleInput {
+    id: Option<String>,
+    title: String,
+    #[serde(default)]
+    summary: String,
+    #[serde(default)]
+    content: String,
+    url: String,
+    /// RFC 3339 timestamp.
+    created_time: DateTime<Utc>,
+}
+
+impl ArticleInput {
+    /// Checks the article, `path_id` is the id of the url being written to if any.
+    fn validate(self, path_id: Option<&str>) -> Result<Article, Error> {
+        let mut errors: Vec<(&'static str, &'static str)> = Vec::new();
+        let id = match (path_id, self.id) {
+            (Some(path_id), Some(id)) if id != path_id => {
+                errors.push(("id", "does not match the url"));
+                id
+            }
+            (Some(path_id), _) => path_id.to_string(),
+            (None, Some(id)) => id,
+            (None, None) => {
+                errors.push(("id", "is required"));
+                String::new()
+            }
+        };
+        if !id.is_empty() && id.trim() != id {
+            errors.push(("id", "must not start or end with whitespace"));
+        }
+        if path_id.is_none() && id.is_empty() {
+            errors.push(("id", "must not be empty"));
+        }
+        if self.title.trim().is_empty() {
+            errors.push(("title", "must not be empty"));
+        }
+        if self.url.trim().is_empty() {
+            errors.push(("url", "must not be empty"));
+        }
+        if !errors.is_empty() {
+            errors.dedup_by_key(|(key, _)| *key);
+            return Err(Error::unprocessable_entity(errors));
+        }
+        Ok(Article {
+            id,
+            title: self.title,
+            summary: self.summary,
+            content: self.content,
+            url: self.url,
+            timestamp: self.created_time,
+        })
+    }
+}
+
+/// Applies `ops` with the writer of the server and waits until they are searchable.
+/// Returns the opstamp of the commit.
+async fn apply(app_state: &AppState, ops: Vec<IndexOp>) -> Result<Opstamp, Error> {
+    let writer = app_state
+        .writer()
+        .ok_or_else(|| anyhow::anyhow!("the server was started without an index writer"))?;
+    Ok(writer.apply(ops).await?)
+}
+
+#[derive(Debug, Serialize)]
+pub struct WriteResponse {
+    id: String,
+    /// Opstamp of the commit that made the change visible.
+    opstamp: Opstamp,
+}
+
+/// `POST /api/articles`, fails when an article with the same id exists.
+pub async fn create_article(
+    _: Authorized,
+    State(app_state): State<AppState>,
+    Json(input): Json<ArticleInput>,
+) -> Result<(StatusCode, Json<WriteResponse>), Error> {
+    let article = input.validate(None)?;
+    let id = article.id.clone();
+    // The insert, or without a database the writer, is the check so two requests
+    // creating the same article cannot both succeed.
+    let op = match &app_state.pool {
+        Some(pool) => {
+            if is_indexed(&app_state.reader().searcher(), &article.id)?
+                || !article.insert(pool).await?
+            {
+                return Err(Error::unprocessable_entity([("id", "already exists")]));
+            }
+            IndexOp::Upsert(article)
+        }
+        None => IndexOp::Create(article),
+    };
+    let opstamp = apply(&app_state, vec![op]).await?;
+    Ok((StatusCode::CREATED, Json(WriteResponse { id, opstamp })))
+}
+
+/// `PUT /api/articles/:id`, creates the article or replaces it.
+pub async fn update_article(
+    _: Authorized,
+    State(app_state): State<AppState>,
+    Path(id): Path<String>,
+    Json(input): Json<ArticleInput>,
+) -> Result<Json<WriteResponse>, Error> {
+    let article = input.validate(Some(&id))?;
+    if let Some(pool) = &app_state.pool {
+        article.save(pool).await?;
+    }
+    let opstamp = apply(&app_state, vec![IndexOp::Upsert(article)]).await?;
+    Ok(Json(WriteResponse { id, opstamp }))
+}
+
+/// `DELETE /api/articles/:id`
+pub async fn delete_article(
+    _: Authorized,
+    State(app_state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<WriteResponse>, Error> {
+    let mut found = is_indexed(&app_state.reader().searcher(), &id)?;
+    if let Some(pool) = &app_state.pool {
+        found |= Article::delete(pool, &id).await?;
+    }
+    if !found {
+        return Err(Error::NotFound);
+    }
+    let opstamp = apply(&app_state, vec![IndexOp::Delete(id.clone())]).await?;
+    Ok(Json(WriteResponse { id, opstamp }))
+}
+
+/// A line of a bulk request that was not written.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RejectedLine {
+    pub line: usize,
+    pub reason: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BulkResponse {
+    pub written: usize,
+    pub rejected: Vec<RejectedLine>,
+    pub opstamp: Opstamp,
+}
+
+/// `POST /api/articles/bulk`, creates or replaces the articles of a newline delimited
+/// JSON body, one article per line, in a single commit. Invalid lines are reported
+/// and the others still written.
+pub async fn bulk_articles(
+    _: Authorized,
+    State(app_state): State<AppState>,
+    body: String,
+) -> Result<Json<BulkResponse>, Error> {
+    let mut ops = Vec::new();
+    let mut rejected = Vec::new();
+    for (i, line) in body.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let article = serde_json::from_str::<ArticleInput>(line)
+            .map_err(|e| e.to_string())
+            .and_then(|input| {
+                input.validate(None).map_err(|e| match e {
+                    Error::UnprocessableEntity { errors } => {
+                        let mut errors: Vec<_> = errors
+                            .into_iter()
+                            .map(|(key, messages)| format!("{key} {}", messages.join(", ")))
+                            .collect();
+                        errors.sort();
+                        errors.join("; ")
+                    }
+                    e => e.to_string(),
+                })
+            });
+        match article {
+            Ok(article) => ops.push(IndexOp::Upsert(article)),
+            Err(reason) => rejected.push(RejectedLine {
+                line: i + 1,
+                reason,
+            }),
+        }
+    }
+    if let Some(pool) = &app_state.pool {
+        let mut transaction = pool.begin().await?;
+        for op in &ops {
+            if let IndexOp::Upsert(article) = op {
+                article.save(&mut *transaction).await?;
+            }
+        }
+        transaction.commit().await?;
+    }
+    let written = ops.len();
+    let opstamp = apply(&app_state, ops).await?;
+    Ok(Json(BulkResponse {
+        written,
+        rejected,
+        opstamp,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::article::{get_article, Article};
+    use crate::config::CommitConfig;
+    use crate::db::TestDatabase;
+    use crate::error::Error;
+    use crate::wrapper::{query_wrapper, SearchOptions};
+    use crate::write::{
+        authorize, bulk_articles, create_article, delete_article, update_article, ArticleInput,
+        Authorized,
+    };
+    use crate::AppState;
+    use crate::{test_article, test_state};
+    use axum::extract::{Path, State};
+    use axum::http::header::AUTHORIZATION;
+    use axum::http::{HeaderMap, HeaderValue};
+    use axum::Json;
+
+    const TOKEN: &str = "0123456789abcdef";
+
+    fn writable_state() -> AppState {
+        // Commits soon after each write instead of batching them.
+        let commit = CommitConfig {
+            interval_ms: 10,
+            ..CommitConfig::default()
+        };
+        test_state(&[])
+            .with_writer(TOKEN.to_string(), 15_000_000, commit)
+            .unwrap()
+    }
+
+    fn input(json: &str) -> Json<ArticleInput> {
+        Json(serde_json::from_str(json).unwrap())
+    }
+
+    fn titles(state: &AppState, query: &str) -> Vec<String> {
+        let results = query_wrapper(state, query, &SearchOptions::default()).unwrap();
+        results.hits.into_iter().map(|hit| hit.title).collect()
+    }
+
+    #[test]
+    fn test_authorize() {
+        let headers = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(AUTHORIZATION, HeaderValue::from_str(value).unwrap());
+            headers
+        };
+        let bearer = headers(&format!("Bearer {TOKEN}"));
+        assert!(authorize(Some(TOKEN), &bearer).is_ok());
+        assert!(authorize(Some(TOKEN), &headers(&format!("Token {TOKEN}"))).is_ok());
+        assert!(matches!(authorize(None, &bearer), Err(Error::Forbidden)));
+        for wrong in [
+            HeaderMap::new(),
+            headers(TOKEN),
+            headers("Bearer 0123456789abcdeF"),
+            headers("Bearer 0123"),
+        ] {
+            assert!(matches!(
+                authorize(Some(TOKEN), &wrong),
+                Err(Error::Unauthorized)
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_writes_are_searchable_once_returned() {
+        let state = writable_state();
+        let article = r#"{"id": "1", "title": "Bão số 9", "url": "/xa-hoi/1.htm",
+            "created_time": "2023-11-01T10:00:00Z"}"#;
+        let (status, _) = create_article(Authorized(()), State(state.clone()), input(article))
+            .await
+            .unwrap();
+        assert_eq!(status, 201);
+        assert_eq!(titles(&state, "bão"), ["Bão số 9"]);
+        let duplicate = create_article(Authorized(()), State(state.clone()), input(article)).await;
+        assert!(matches!(duplicate, Err(Error::UnprocessableEntity { .. })));
+
+        let updated = r#"{"title": "Bão số 10", "url": "/xa-hoi/1.htm",
+            "created_time": "2023-11-01T10:00:00Z"}"#;
+        let Json(response) = update_article(
+            Authorized(()),
+            State(state.clone()),
+            Path("1".to_string()),
+            input(updated),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.id, "1");
+        assert_eq!(titles(&state, "bão"), ["Bão số 10"]);
+        let mismatch = update_article(
+            Authorized(()),
+            State(state.clone()),
+            Path("2".to_string()),
+            input(&updated.replace('{', r#"{"id": "1", "#)),
+        )
+        .await;
+        assert!(matches!(mismatch, Err(Error::UnprocessableEntity { .. })));
+
+        let deleted =
+            delete_article(Authorized(()), State(state.clone()), Path("1".to_string())).await;
+        assert!(deleted.is_ok());
+        assert!(titles(&state, "bão").is_empty());
+        let missing =
+            delete_article(Authorized(()), State(state.clone()), Path("1".to_string())).await;
+        assert!(matches!(missing, Err(Error::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_creates_of_an_article_succeed_once() {
+        let state = writable_state();
+        let create = |title: &str| {
+            let article = format!(
+                r#"{{"id": "1", "title": "{title}", "url": "/xa-hoi/1.htm",
+                    "created_time": "2023-11-01T10:00:00Z"}}"#
+            );
+            create_article(Authorized(()), State(state.clone()), input(&article))
+        };
+        let (first, second) = tokio::join!(create("Bão số 9"), create("Bão số 10"));
+        let created = [&first, &second]
+            .iter()
+            .filter(|result| result.is_ok())
+            .count();
+        assert_eq!(created, 1);
+        assert!([first, second]
+            .into_iter()
+            .any(|result| matches!(result, Err(Error::UnprocessableEntity { .. }))));
+        assert_eq!(titles(&state, "bão").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_bulk_reports_invalid_lines() {
+        let state = writable_state();
+        let body = r#"{"id": "1", "title": "Bão số 9", "url": "/1.htm", "created_time": "2023-11-01T10:00:00Z"}
+
+{"id": "2", "title": " ", "url": "/2.htm", "created_time": "2023-11-01T10:00:00Z"}
+{"id": "3", "title": "Bão tan", "url": "/3.htm", "created_time": "2023-11-02T10:00:00Z"}
+not json
+"#;
+        let Json(response) = bulk_articles(Authorized(()), State(state.clone()), body.to_string())
+            .await
+            .unwrap();
+        assert_eq!(response.written, 2);
+        let lines: Vec<_> = response.rejected.iter().map(|line| line.line).collect();
+        assert_eq!(lines, [3, 5]);
+        assert_eq!(response.rejected[0].reason, "title must not be empty");
+        assert_eq!(titles(&state, "bão").len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_writes_go_through_postgres() {
+        let Some(db) = TestDatabase::create().await else {
+            return;
+        };
+        let state = writable_state().with_pool(db.pool.clone());
+        let article = r#"{"id": "1", "title": "Bão số 9", "url": "/xa-hoi/1.htm",
+            "created_time": "2023-11-01T10:00:00Z"}"#;
+        let created = create_article(Authorized(()), State(state.clone()), input(article)).await;
+        assert!(created.is_ok());
+        assert!(Article::find(&db.pool, "1").await.unwrap().is_some());
+        let Json(found) = get_article(State(state.clone()), Path("1".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(found.title, "Bão số 9");
+        // Only in Postgres, as written by another server.
+        let other = test_article("2", "Mưa lớn", 2);
+        other.save(&db.pool).await.unwrap();
+        let created = create_article(
+            Authorized(()),
+            State(state.clone()),
+            input(
+                r#"{"id": "2", "title": "Nắng nóng", "url": "/xa-hoi/2.htm",
+                "created_time": "2023-11-02T10:00:00Z"}"#,
+            ),
+        )
+        .await;
+        assert!(matches!(created, Err(Error::UnprocessableEntity { .. })));
+        assert_eq!(
+            Article::find(&db.pool, "2").await.unwrap().unwrap().title,
+            "Mưa lớn"
+        );
+
+        // A bulk request is written in one transaction.
+        let body = r#"{"id": "3", "title": "Lũ quét", "url": "/3.htm", "created_time": "2023-11-03T10:00:00Z"}
+{"id": "4", "title": "Sạt lở", "url": "/4.htm", "created_time": "2023-11-04T10:00:00Z"}"#;
+        let bulk = bulk_articles(Authorized(()), State(state.clone()), body.to_string()).await;
+        assert!(bulk.is_ok());
+        assert!(Article::find(&db.pool, "4").await.unwrap().is_some());
+
+        let deleted =
+            delete_article(Authorized(()), State(state.clone()), Path("1".to_string())).await;
+        assert!(deleted.is_ok());
+        assert!(Article::find(&db.pool, "1").await.unwrap().is_none());
+        db.destroy().await;
+    }
+}
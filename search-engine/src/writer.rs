@@ -0,0 +1,577 @@
+//! The only `IndexWriter` of the server, owned by a background thread.
+//!
+//! Handlers send their additions and deletions over a channel. The thread applies
+//! them as they arrive and commits once `max_pending` operations are waiting or
+//! `interval` after the first of them, so a burst of writes shares one commit. The
+//! shared reader is reloaded after every commit, before anyone waiting on the
+//! commit is woken up, so an operation is searchable once `wait_for` its opstamp
+//! returns.
+use crate::article::{is_indexed, Article};
+use crate::config::CommitConfig;
+use crate::error::Error;
+use serde::Serialize;
+use sqlx::types::chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Instant;
+use tantivy::{IndexReader, IndexWriter, Opstamp, Term, UserOperation};
+use tokio::sync::{oneshot, watch};
+
+/// A change to the index.
+#[derive(Debug)]
+pub enum IndexOp {
+    Upsert(Article),
+    /// Adds the article, fails with `WriterError::Exists` when its id is indexed or
+    /// pending.
+    Create(Article),
+    Delete(String),
+}
+
+#[derive(thiserror::Error, Debug, Clone)]
+pub enum WriterError {
+    #[error("the index writer has stopped")]
+    Stopped,
+
+    /// The operations waiting for the commit were rolled back.
+    #[error("the index writer failed: {0}")]
+    Failed(String),
+
+    /// A batch was refused, it creates an article that exists.
+    #[error("article {0} already exists")]
+    Exists(String),
+}
+
+impl From<WriterError> for Error {
+    fn from(error: WriterError) -> Self {
+        match error {
+            WriterError::Exists(_) => Error::unprocessable_entity([("id", "already exists")]),
+            error => Error::Anyhow(error.into()),
+        }
+    }
+}
+
+/// Progress of the writer, as reported by `/health`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CommitStatus {
+    /// Opstamp of the last commit, operations with a smaller opstamp are searchable.
+    pub committed_opstamp: Opstamp,
+    /// Operations waiting for the next commit.
+    pub pending: usize,
+    pub last_commit: Option<DateTime<Utc>>,
+    /// Why the last commit failed, cleared by the next successful one.
+    pub last_error: Option<String>,
+}
+
+type Reply<T> = oneshot::Sender<Result<T, WriterError>>;
+
+enum Command {
+    /// Replies with the opstamp of the last operation once they are all applied.
+    Apply(Vec<IndexOp>, Reply<Opstamp>),
+    /// Replies with the opstamp of the first commit covering the given opstamp.
+    Wait(Opstamp, Reply<Opstamp>),
+    /// Commits the pending operations right away.
+    Commit(Reply<Opstamp>),
+    /// Commits the pending operations and stops the thread.
+    Shutdown(Reply<Opstamp>),
+}
+
+/// Handle to the writer thread, cheap to clone. The thread commits what is pending
+/// and stops once every handle is dropped.
+#[derive(Clone)]
+pub struct WriterHandle {
+    commands: mpsc::Sender<Command>,
+    status: watch::Receiver<CommitStatus>,
+}
+
+impl WriterHandle {
+    /// Moves `writer` to a new thread, `reader` is reloaded after each of its commits.
+    pub fn spawn(
+        writer: IndexWriter,
+        reader: IndexReader,
+        config: CommitConfig,
+    ) -> std::io::Result<WriterHandle> {
+        let (commands, receiver) = mpsc::channel();
+        let (mut thread, status) = WriterThread::new(writer, reader, config);
+        std::thread::Builder::new()
+            .name("index-writer".to_string())
+            .spawn(move || {
+                // The writer is dropped, which releases the index, before a shutdown
+                // is answered.
+                if let Some((reply, result)) = thread.run(receiver) {
+                    drop(thread);
+                    let _ = reply.send(result);
+                }
+            })?;
+        Ok(WriterHandle { commands, status })
+    }
+
+    async fn send<T>(&self, command: impl FnOnce(Reply<T>) -> Command) -> Result<T, WriterError> {
+        let (reply, receiver) = oneshot::channel();
+        self.commands
+            .send(command(reply))
+            .map_err(|_| WriterError::Stopped)?;
+        receiver.await.map_err(|_| WriterError::Stopped)?
+    }
+
+    /// Queues `ops` for the next commit and returns the opstamp of the last one,
+    /// without waiting for them to be searchable.
+    pub async fn submit(&self, ops: Vec<IndexOp>) -> Result<Opstamp, WriterError> {
+        self.send(|reply| Command::Apply(ops, reply)).await
+    }
+
+    /// Waits until the operation stamped `opstamp`, and every one before it, is
+    /// searchable. Returns the opstamp of the commit.
+    pub async fn wait_for(&self, opstamp: Opstamp) -> Result<Opstamp, WriterError> {
+        self.send(|reply| Command::Wait(opstamp, reply)).await
+    }
+
+    /// Queues `ops` and waits for the commit making them searchable.
+    pub async fn apply(&self, ops: Vec<IndexOp>) -> Result<Opstamp, WriterError> {
+        let opstamp = self.submit(ops).await?;
+        self.wait_for(opstamp).await
+    }
+
+    /// Commits the pending operations without waiting for a threshold.
+    pub async fn commit(&self) -> Result<Opstamp, WriterError> {
+        self.send(Command::Commit).await
+    }
+
+    /// Commits the pending operations and stops the writer, later calls fail with
+    /// `WriterError::Stopped`.
+    pub async fn shutdown(&self) -> Result<Opstamp, WriterError> {
+        self.send(Command::Shutdown).await
+    }
+
+    pub fn status(&self) -> CommitStatus {
+        self.status.borrow().clone()
+    }
+}
+
+struct WriterThread {
+    writer: IndexWriter,
+    reader: IndexReader,
+    config: CommitConfig,
+    status: watch::Sender<CommitStatus>,
+    /// Operations applied since the last commit.
+    pending: usize,
+    /// Ids written since the last commit, and whether they exist after it.
+    pending_ids: HashMap<String, bool>,
+    /// When the pending operations must be committed at the latest.
+    deadline: Option<Instant>,
+    /// Opstamp of the last operation applied.
+    last_opstamp: Opstamp,
+    waiters: Vec<(Opstamp, Reply<Opstamp>)>,
+    /// Opstamps of the operations discarded by failed commits, as `(after, to]` ranges.
+    lost: Vec<(Opstamp, Opstamp)>,
+    /// Added to the opstamps of the writer, which stamps from its last commit again
+    /// after a rollback, so an opstamp is never handed out twice.
+    offset: Opstamp,
+}
+
+impl WriterThread {
+    fn new(
+        writer: IndexWriter,
+        reader: IndexReader,
+        config: CommitConfig,
+    ) -> (WriterThread, watch::Receiver<CommitStatus>) {
+        let committed_opstamp = writer.commit_opstamp();
+        let (status_sender, status) = watch::channel(CommitStatus {
+            committed_opstamp,
+            ..CommitStatus::default()
+        });
+        let thread = WriterThread {
+            writer,
+            reader,
+            config,
+            status: status_sender,
+            pending: 0,
+            pending_ids: HashMap::new(),
+            deadline: None,
+            last_opstamp: committed_opstamp,
+            waiters: Vec::new(),
+            lost: Vec::new(),
+            offset: 0,
+        };
+        (thread, status)
+    }
+
+    /// Returns the reply to a shutdown with its result, if one stopped the thread.
+    fn run(
+        &mut self,
+        commands: mpsc::Receiver<Command>,
+    ) -> Option<(Reply<Opstamp>, Result<Opstamp, WriterError>)> {
+        loop {
+            let command = match self.deadline {
+                Some(deadline) => {
+                    match commands.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                    {
+                        Ok(command) => Some(command),
+                        Err(RecvTimeoutError::Timeout) => None,
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+                None => match commands.recv() {
+                    Ok(command) => Some(command),
+                    Err(_) => break,
+                },
+            };
+            match command {
+                Some(Command::Apply(ops, reply)) => {
+                    let result = self.apply(ops);
+                    // The status counts the operations before their sender goes on.
+                    self.publish();
+                    let _ = reply.send(result);
+                    if self.pending >= self.config.max_pending {
+                        // Failures are logged and reported to the waiters.
+                        let _ = self.commit();
+                    }
+                }
+                Some(Command::Wait(opstamp, reply)) => self.wait(opstamp, reply),
+                Some(Command::Commit(reply)) => {
+                    let _ = reply.send(self.commit());
+                }
+                Some(Command::Shutdown(reply)) => return Some((reply, self.commit())),
+                // The deadline passed.
+                None => {
+                    let _ = self.commit();
+                }
+            }
+            self.publish();
+        }
+        let _ = self.commit();
+        None
+    }
+
+    /// `IndexWriter::commit_opstamp` only knows the commit the writer was opened at.
+    fn committed_opstamp(&self) -> Opstamp {
+        self.status.borrow().committed_opstamp
+    }
+
+    /// Whether `id` exists once the pending operations, then `written`, are committed.
+    fn exists(&self, id: &str, written: &[(String, bool)]) -> Result<bool, WriterError> {
+        let pending = written
+            .iter()
+            .rev()
+            .find(|(written, _)| written == id)
+            .map(|(_, exists)| *exists)
+            .or_else(|| self.pending_ids.get(id).copied());
+        match pending {
+            Some(exists) => Ok(exists),
+            None => is_indexed(&self.reader.searcher(), id)
+                .map_err(|e| WriterError::Failed(e.to_string())),
+        }
+    }
+
+    /// Applies `ops` as a single batch, so they are either all pending or, when the
+    /// writer fails, all rolled back along with the rest of the pending operations.
+    /// Nothing is applied when the batch creates an article that exists.
+    fn apply(&mut self, ops: Vec<IndexOp>) -> Result<Opstamp, WriterError> {
+        if ops.is_empty() {
+            // Waiting on it waits for the next commit.
+            return Ok(self.committed_opstamp());
+        }
+        let schema = self.writer.index().schema();
+        let id_field = schema
+            .get_field("id")
+            .map_err(|e| WriterError::Failed(e.to_string()))?;
+        let count = ops.len();
+        let mut operations = Vec::with_capacity(count * 2);
+        let mut written = Vec::with_capacity(count);
+        for op in ops {
+            match op {
+                IndexOp::Upsert(article) => {
+                    operations.push(UserOperation::Delete(Term::from_field_text(
+                        id_field,
+                        &article.id,
+                    )));
+                    operations.push(UserOperation::Add(article.to_document(&schema)));
+                    written.push((article.id, true));
+                }
+                IndexOp::Create(article) => {
+                    if self.exists(&article.id, &written)? {
+                        return Err(WriterError::Exists(article.id));
+                    }
+                    operations.push(UserOperation::Add(article.to_document(&schema)));
+                    written.push((article.id, true));
+                }
+                IndexOp::Delete(id) => {
+                    operations.push(UserOperation::Delete(Term::from_field_text(id_field, &id)));
+                    written.push((id, false));
+                }
+            }
+        }
+        let opstamp = match self.writer.run(operations) {
+            Ok(opstamp) => opstamp + self.offset,
+            Err(e) => {
+                tracing::error!("index write failed: {:?}", e);
+                let error = WriterError::Failed(e.to_string());
+                self.roll_back(&error);
+                return Err(error);
+            }
+        };
+        self.pending += count;
+        self.pending_ids.extend(written);
+        self.last_opstamp = opstamp;
+        if self.deadline.is_none() {
+            self.deadline = Some(Instant::now() + self.config.interval());
+        }
+        Ok(opstamp)
+    }
+
+    fn wait(&mut self, opstamp: Opstamp, reply: Reply<Opstamp>) {
+        let committed = self.committed_opstamp();
+        if self
+            .lost
+            .iter()
+            .any(|&(after, to)| after < opstamp && opstamp <= to)
+        {
+            let _ = reply.send(Err(WriterError::Failed(
+                "the operation was rolled back".to_string(),
+            )));
+        } else if opstamp < committed {
+            let _ = reply.send(Ok(committed));
+        } else {
+            self.waiters.push((opstamp, reply));
+            // Nothing may be pending, make sure a commit comes.
+            self.deadline
+                .get_or_insert_with(|| Instant::now() + self.config.interval());
+        }
+    }
+
+    /// Commits and reloads the reader, then wakes up the waiters. Returns the opstamp
+    /// of the last commit, even when nothing was pending.
+    fn commit(&mut self) -> Result<Opstamp, WriterError> {
+        self.deadline = None;
+        if self.pending == 0 && self.waiters.is_empty() {
+            return Ok(self.committed_opstamp());
+        }
+        let result = self.writer.commit().and_then(|opstamp| {
+            self.reader.reload()?;
+            Ok(opstamp + self.offset)
+        });
+        match result {
+            Ok(opstamp) => {
+                self.pending = 0;
+                self.pending_ids.clear();
+                tracing::debug!("index committed at opstamp {}", opstamp);
+                self.status.send_modify(|status| {
+                    status.committed_opstamp = opstamp;
+                    status.last_commit = Some(Utc::now());
+                    status.last_error = None;
+                });
+                self.wake_up(Ok(opstamp));
+                Ok(opstamp)
+            }
+            Err(e) => {
+                tracing::error!("index commit failed: {:?}", e);
+                let error = WriterError::Failed(e.to_string());
+                self.roll_back(&error);
+                Err(error)
+            }
+        }
+    }
+
+    /// Discards the pending operations and fails everyone waiting for them.
+    fn roll_back(&mut self, error: &WriterError) {
+        if let Err(e) = self.writer.rollback() {
+            tracing::error!("index rollback failed: {:?}", e);
+        }
+        self.lost
+            .push((self.committed_opstamp(), self.last_opstamp));
+        // A commit that succeeded before the reader failed to reload is not undone.
+        let restarted_at = self.writer.commit_opstamp() + self.offset;
+        self.offset += self.last_opstamp.saturating_sub(restarted_at);
+        self.pending = 0;
+        self.pending_ids.clear();
+        self.deadline = None;
+        self.status
+            .send_modify(|status| status.last_error = Some(error.to_string()));
+        self.wake_up(Err(error.clone()));
+    }
+
+    fn wake_up(&mut self, result: Result<Opstamp, WriterError>) {
+        for (_, reply) in self.waiters.drain(..) {
+            let _ = reply.send(result.clone());
+        }
+    }
+
+    fn publish(&self) {
+        let pending = self.pending;
+        self.status.send_if_modified(|status| {
+            let modified = status.pending != pending;
+            status.pending = pending;
+            modified
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::CommitConfig;
+    use crate::writer::{IndexOp, WriterError, WriterHandle, WriterThread};
+    use crate::AppState;
+    use crate::{test_article, test_state};
+    use std::time::Duration;
+    use tokio::sync::oneshot;
+
+    fn spawn(state: &AppState, config: CommitConfig) -> WriterHandle {
+        let writer = state.index().writer(15_000_000).unwrap();
+        WriterHandle::spawn(writer, state.reader().clone(), config).unwrap()
+    }
+
+    fn num_docs(state: &AppState) -> u64 {
+        state.reader().searcher().num_docs()
+    }
+
+    #[tokio::test]
+    async fn test_commits_on_size_or_time() {
+        let state = test_state(&[]);
+        let writer = spawn(
+            &state,
+            CommitConfig {
+                max_pending: 2,
+                interval_ms: 60_000,
+            },
+        );
+        let first = writer
+            .submit(vec![IndexOp::Upsert(test_article("1", "Bài số 1", 1))])
+            .await
+            .unwrap();
+        assert_eq!(writer.status().pending, 1);
+        assert_eq!(num_docs(&state), 0);
+        // The second operation reaches `max_pending`.
+        let second = writer
+            .submit(vec![IndexOp::Upsert(test_article("2", "Bài số 2", 1))])
+            .await
+            .unwrap();
+        assert!(second > first);
+        let committed = writer.wait_for(second).await.unwrap();
+        assert!(committed > second);
+        assert_eq!(num_docs(&state), 2);
+        assert_eq!(writer.status().committed_opstamp, committed);
+        assert_eq!(writer.status().pending, 0);
+
+        let state = test_state(&[]);
+        let writer = spawn(
+            &state,
+            CommitConfig {
+                max_pending: 1000,
+                interval_ms: 20,
+            },
+        );
+        let opstamp = writer
+            .submit(vec![IndexOp::Upsert(test_article("1", "Bài số 1", 1))])
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(num_docs(&state), 1);
+        // Already committed, answers right away.
+        assert!(writer.wait_for(opstamp).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_apply_is_searchable_once_returned() {
+        let state = test_state(&[test_article("1", "Bài số 1", 1)]);
+        let writer = spawn(&state, CommitConfig::default());
+        let committed = writer
+            .apply(vec![
+                IndexOp::Delete("1".to_string()),
+                IndexOp::Upsert(test_article("2", "Bài số 2", 1)),
+                IndexOp::Upsert(test_article("2", "Bài số 2", 1)),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(num_docs(&state), 1);
+        // Nothing is pending.
+        assert_eq!(writer.commit().await.unwrap(), committed);
+
+        writer
+            .submit(vec![IndexOp::Upsert(test_article("3", "Bài số 3", 1))])
+            .await
+            .unwrap();
+        assert!(writer.shutdown().await.unwrap() > committed);
+        assert_eq!(num_docs(&state), 2);
+        assert!(matches!(
+            writer.submit(Vec::new()).await,
+            Err(WriterError::Stopped)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_create_fails_when_indexed_or_pending() {
+        let state = test_state(&[test_article("1", "Bài số 1", 1)]);
+        let writer = spawn(
+            &state,
+            CommitConfig {
+                interval_ms: 60_000,
+                ..CommitConfig::default()
+            },
+        );
+        let exists = |result| matches!(result, Err(WriterError::Exists(id)) if id == "1");
+        assert!(exists(
+            writer
+                .submit(vec![IndexOp::Create(test_article("1", "Bài số 1", 1))])
+                .await
+        ));
+        writer
+            .submit(vec![IndexOp::Delete("1".to_string())])
+            .await
+            .unwrap();
+        writer
+            .submit(vec![IndexOp::Create(test_article("1", "Bài số 1", 1))])
+            .await
+            .unwrap();
+        // Pending, not committed yet.
+        assert!(exists(
+            writer
+                .submit(vec![IndexOp::Create(test_article("1", "Bài số 1", 1))])
+                .await
+        ));
+        // Within the batch, and nothing of the batch is applied.
+        let batch = vec![
+            IndexOp::Create(test_article("2", "Bài số 2", 1)),
+            IndexOp::Create(test_article("2", "Bài số 2", 1)),
+        ];
+        assert!(matches!(
+            writer.submit(batch).await,
+            Err(WriterError::Exists(id)) if id == "2"
+        ));
+        writer.commit().await.unwrap();
+        assert_eq!(num_docs(&state), 1);
+        assert!(exists(
+            writer
+                .submit(vec![IndexOp::Create(test_article("1", "Bài số 1", 1))])
+                .await
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_rolled_back_operations_fail_and_opstamps_go_on() {
+        let state = test_state(&[]);
+        let writer = state.index().writer(15_000_000).unwrap();
+        let (mut thread, _) =
+            WriterThread::new(writer, state.reader().clone(), CommitConfig::default());
+        let lost = thread
+            .apply(vec![IndexOp::Upsert(test_article("1", "Bài số 1", 1))])
+            .unwrap();
+        thread.roll_back(&WriterError::Failed("disk full".to_string()));
+        assert_eq!(thread.pending, 0);
+
+        let kept = thread
+            .apply(vec![IndexOp::Upsert(test_article("2", "Bài số 2", 1))])
+            .unwrap();
+        assert!(kept > lost);
+        let (reply, lost_reply) = oneshot::channel();
+        thread.wait(lost, reply);
+        assert!(matches!(
+            lost_reply.await.unwrap(),
+            Err(WriterError::Failed(_))
+        ));
+        let (reply, kept_reply) = oneshot::channel();
+        thread.wait(kept, reply);
+        let committed = thread.commit().unwrap();
+        assert!(committed > kept);
+        assert_eq!(kept_reply.await.unwrap().unwrap(), committed);
+        assert_eq!(num_docs(&state), 1);
+    }
+}
@@ -14,6 +14,24 @@ export default function ShowcaseHN() {
   const [jobResults, setJobResults] = useState([]);
   const [jobCount, setJobCount] = useState(0);
   const [query, setQuery] = useState("");
+  const [suggestions, setSuggestions] = useState([]);
+  const [correction, setCorrection] = useState(null);
+
+  useEffect(() => {
+    if (query.trim().length === 0) {
+      setSuggestions([]);
+      return;
+    }
+    const controller = new AbortController();
+    axios
+      .get(`${SERVER_ADDRESS}/api/suggest`, {
+        params: { q: query },
+        signal: controller.signal,
+      })
+      .then((res) => setSuggestions(res.data?.suggestions ?? []))
+      .catch(() => {});
+    return () => controller.abort();
+  }, [query]);
   function Items({ currentItems }) {
     return (
       <>
@@ -90,20 +108,17 @@ export default function ShowcaseHN() {
     await search();
   };
 
-  const search = async () => {
+  const search = async (text = query) => {
     setLoading(true);
     setSuccess(false);
     try {
       const res = await axios.post(`${SERVER_ADDRESS}/api/articles/query`, {
-        query: query,
-      });
-      const data = res.data?.data;
-      const result = { data: [] };
-      data.forEach((item) => {
-        result.data.push(JSON.parse(item));
+        query: text,
+        mode: "simple",
       });
-      setJobResults(result.data);
+      setJobResults(res.data?.data ?? []);
       setJobCount(res.data.article_count);
+      setCorrection(res.data?.suggestion ?? null);
     } catch (error) {
       console.error(error);
     } finally {
@@ -126,8 +141,15 @@ export default function ShowcaseHN() {
           placeholder="Tìm kiếm"
           className="flex-1 p-2 border rounded"
           value={query}
+          list="suggestions"
+          autoComplete="off"
           onChange={(e) => setQuery(e.target.value)}
         />
+        <datalist id="suggestions">
+          {suggestions.map((s) => (
+            <option key={s.text} value={s.text} />
+          ))}
+        </datalist>
       </form>
       <hr />
       <button
@@ -142,6 +164,21 @@ export default function ShowcaseHN() {
       {!loading && (
         <h3 className="text-xl font-medium">{jobCount} results found</h3>
       )}
+      {!loading && correction && (
+        <p>
+          Có phải bạn muốn tìm{" "}
+          <button
+            className="font-medium text-blue-600"
+            onClick={() => {
+              setQuery(correction);
+              search(correction);
+            }}
+          >
+            {correction}
+          </button>
+          ?
+        </p>
+      )}
       {/*<!-- block: ready to try? -->*/}
       <div className="">
         <div className="max-w-4xl">
@@ -89,6 +89,12 @@ export default function ShowcaseHN() {
                 <div className="flex flex-col flex-wrap w-full gap-2 ">
                   <div className="flex-1 font-bold">{job.title}</div>
                   <div className="font-medium shrink-0">{job.summary}</div>
+                  {job.snippets?.content && (
+                    <div
+                      className="text-sm text-slate-600"
+                      dangerouslySetInnerHTML={{ __html: job.snippets.content }}
+                    />
+                  )}
                   <a
                     href={`https://dantri.com.vn${job.url}`}
                     target="_blank"
@@ -205,13 +211,9 @@ export default function ShowcaseHN() {
     try {
       const res = await axios.post(`${SERVER_ADDRESS}/api/articles/query`, {
         query: parseFieldsToQuery(),
+        mode: "advanced",
       });
-      const data = res.data?.data;
-      const result = { data: [] };
-      data.forEach((item) => {
-        result.data.push(JSON.parse(item));
-      });
-      setJobResults(result.data);
+      setJobResults(res.data?.data ?? []);
       setJobCount(res.data.article_count);
     } catch (error) {
       console.error(error);
@@ -224,17 +226,11 @@ export default function ShowcaseHN() {
     setLoading(true);
     setSuccess(false);
     try {
-      const res = await axios.get(`${SERVER_ADDRESS}/load`);
-      const data = res.data;
-      if (data.results?.length > 0) {
-        setJobResults(
-          data.results.sort((a, b) => b.relevanceScore - a.relevanceScore)
-        );
-      }
-      if (data.criteria?.length > 0) {
-        setFields(data.criteria);
-      }
-      setJobCount(data.results?.length);
+      const res = await axios.get(`${SERVER_ADDRESS}/load`, {
+        params: { limit: 100 },
+      });
+      setJobResults(res.data?.data ?? []);
+      setJobCount(res.data.article_count);
     } catch (error) {
       console.error(error);
     } finally {